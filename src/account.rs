@@ -1,54 +1,228 @@
 use crate::types::*;
 
-#[derive(Debug, Clone, PartialEq, Default)]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct Account {
     /// The total funds that are available for trading, staking, withdrawal, etc. This should be equal to the total - held amounts.
-    pub available: Balance,
+    pub available: Money,
     /// The total funds that are held for dispute. This should be equal to total - available amounts.
-    pub held: Balance,
+    pub held: Money,
     /// The total funds that are available or held. This should be equal to available + held.
-    pub total: Balance,
+    #[serde(skip)]
+    pub total: Money,
     /// Whether the account is locked. An account is locked if a charge back occurs
     pub locked: Option<Locked>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Locked {
     pub reason_for_lock: LockReason,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LockReason {
     Chargeback,
 }
 
+impl LockReason {
+    /// A stable, machine-readable identifier for the reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LockReason::Chargeback => "chargeback",
+        }
+    }
+}
+
+/// Returned when an operation would leave the account in an invalid state.
+/// The account is never mutated when an error is returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountError {
+    /// Amounts passed to an account operation must not be negative.
+    NegativeAmount(Money),
+    /// The resulting balance would not fit in a `Money`.
+    Overflow,
+    /// More funds would be released from held than are currently held.
+    InsufficientHeldFunds { held: Money, requested: Money },
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::NegativeAmount(amount) => {
+                write!(f, "amount must not be negative: {}", amount)
+            }
+            AccountError::Overflow => write!(f, "balance would overflow"),
+            AccountError::InsufficientHeldFunds { held, requested } => write!(
+                f,
+                "cannot release {} from held funds of {}",
+                requested, held
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
 impl Account {
-    pub fn deposit(&mut self, amount: f64) {
-        self.available += amount;
+    pub fn deposit(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        self.set_balances(add(self.available, amount)?, self.held)
+    }
+
+    pub fn withdraw(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        self.set_balances(sub(self.available, amount)?, self.held)
+    }
+
+    pub fn freeze_funds(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        self.set_balances(sub(self.available, amount)?, add(self.held, amount)?)
     }
 
-    pub fn withdraw(&mut self, amount: f64) {
-        self.available -= amount;
+    pub fn thaw_funds(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        let held = self.release_held(amount)?;
+        self.set_balances(add(self.available, amount)?, held)
     }
 
-    pub fn freeze_funds(&mut self, amount: f64) {
-        self.available -= amount;
-        self.held += amount;
+    pub fn chargeback(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        let held = self.release_held(amount)?;
+        self.set_balances(self.available, held)?;
+        self.lock();
+        Ok(())
     }
 
-    pub fn thaw_funds(&mut self, amount: f64) {
-        self.available += amount;
-        self.held -= amount;
+    /// Credit funds straight into held, e.g. a disputed withdrawal that may be returned.
+    pub fn credit_held(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        self.set_balances(self.available, add(self.held, amount)?)
     }
 
-    pub fn chargeback(&mut self, amount: f64) {
-        self.held -= amount;
+    /// Remove funds from held without returning them to available.
+    pub fn debit_held(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        let held = self.release_held(amount)?;
+        self.set_balances(self.available, held)
+    }
+
+    /// Release held funds into available and lock the account, reversing a withdrawal.
+    pub fn reverse_withdrawal(&mut self, amount: Money) -> Result<(), AccountError> {
+        self.thaw_funds(amount)?;
+        self.lock();
+        Ok(())
+    }
+
+    /// Release funds held from available and credit them once more, then lock the account,
+    /// reversing a withdrawal whose dispute held the client's own funds.
+    pub fn refund_withdrawal(&mut self, amount: Money) -> Result<(), AccountError> {
+        check_amount(amount)?;
+        let held = self.release_held(amount)?;
+        let available = add(add(self.available, amount)?, amount)?;
+        self.set_balances(available, held)?;
+        self.lock();
+        Ok(())
+    }
+
+    /// Never overflows, every operation keeps it within a `Money`.
+    pub fn total(&self) -> Money {
+        self.available + self.held
+    }
+
+    /// The held balance after releasing `amount`, which must not go negative.
+    fn release_held(&self, amount: Money) -> Result<Money, AccountError> {
+        let held = sub(self.held, amount)?;
+        if held.is_negative() {
+            return Err(AccountError::InsufficientHeldFunds {
+                held: self.held,
+                requested: amount,
+            });
+        }
+        Ok(held)
+    }
+
+    /// Apply new balances, unless their total would not fit in a `Money`.
+    fn set_balances(&mut self, available: Money, held: Money) -> Result<(), AccountError> {
+        add(available, held)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    fn lock(&mut self) {
         self.locked = Some(Locked {
             reason_for_lock: LockReason::Chargeback,
         });
     }
+}
 
-    pub fn total(&self) -> f64 {
-        self.available + self.held
+fn check_amount(amount: Money) -> Result<(), AccountError> {
+    if amount.is_negative() {
+        return Err(AccountError::NegativeAmount(amount));
+    }
+    Ok(())
+}
+
+fn add(a: Money, b: Money) -> Result<Money, AccountError> {
+    a.checked_add(b).ok_or(AccountError::Overflow)
+}
+
+fn sub(a: Money, b: Money) -> Result<Money, AccountError> {
+    a.checked_sub(b).ok_or(AccountError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(s: &str) -> Money {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_account_rejects_invalid_operations() {
+        let mut account = Account::default();
+        account.deposit(money("10")).unwrap();
+        account.freeze_funds(money("4")).unwrap();
+
+        let before = account.clone();
+
+        assert_eq!(
+            account.thaw_funds(money("5")),
+            Err(AccountError::InsufficientHeldFunds {
+                held: money("4"),
+                requested: money("5"),
+            })
+        );
+        assert_eq!(
+            account.chargeback(money("4.0001")),
+            Err(AccountError::InsufficientHeldFunds {
+                held: money("4"),
+                requested: money("4.0001"),
+            })
+        );
+        assert_eq!(
+            account.deposit(money("-1")),
+            Err(AccountError::NegativeAmount(money("-1")))
+        );
+        assert_eq!(
+            account.deposit(money("922337203685477.5807")),
+            Err(AccountError::Overflow)
+        );
+        // Each balance would fit, but their total wouldn't.
+        assert_eq!(
+            account.credit_held(money("922337203685477")),
+            Err(AccountError::Overflow)
+        );
+
+        // Failed operations leave the account untouched.
+        assert_eq!(account, before);
+
+        account.chargeback(money("4")).unwrap();
+        assert_eq!(account.held, money("0"));
+        assert_eq!(account.available, money("6"));
+        assert!(account.locked.is_some());
     }
 }
@@ -0,0 +1,128 @@
+use crate::error::RejectionReason;
+
+use serde::{Deserialize, Serialize};
+
+/// Where a disputable transaction is in its dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DisputeState {
+    /// Never disputed.
+    #[default]
+    Settled,
+    Disputed,
+    /// A dispute was resolved in the client's favour, the tx stands.
+    Resolved,
+    /// The tx was reversed. This state is terminal.
+    ChargedBack,
+}
+
+/// A command that moves a transaction through its dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeAction {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl DisputeState {
+    /// The transition table, every pair not listed is rejected.
+    ///
+    /// | from        | dispute    | resolve   | chargeback  |
+    /// |-------------|------------|-----------|-------------|
+    /// | Settled     | Disputed   | -         | -           |
+    /// | Disputed    | -          | Resolved  | ChargedBack |
+    /// | Resolved    | Disputed * | -         | -           |
+    /// | ChargedBack | -          | -         | -           |
+    ///
+    /// \* only when `allow_redispute` is set.
+    pub fn transition(
+        self,
+        action: DisputeAction,
+        allow_redispute: bool,
+    ) -> Result<DisputeState, RejectionReason> {
+        match (self, action) {
+            (DisputeState::Settled, DisputeAction::Dispute) => Ok(DisputeState::Disputed),
+            (DisputeState::Resolved, DisputeAction::Dispute) if allow_redispute => {
+                Ok(DisputeState::Disputed)
+            }
+            (DisputeState::Resolved, DisputeAction::Dispute) => {
+                Err(RejectionReason::AlreadyResolved)
+            }
+            (DisputeState::Disputed, DisputeAction::Dispute) => {
+                Err(RejectionReason::AlreadyDisputed)
+            }
+            (DisputeState::Disputed, DisputeAction::Resolve) => Ok(DisputeState::Resolved),
+            (DisputeState::Disputed, DisputeAction::Chargeback) => Ok(DisputeState::ChargedBack),
+            (DisputeState::Settled | DisputeState::Resolved, _) => {
+                Err(RejectionReason::NotDisputed)
+            }
+            (DisputeState::ChargedBack, _) => Err(RejectionReason::AlreadyChargedBack),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispute_state_transitions() {
+        use DisputeAction::*;
+        use DisputeState::*;
+
+        let cases = [
+            (Settled, Dispute, true, Ok(Disputed)),
+            (Settled, Resolve, true, Err(RejectionReason::NotDisputed)),
+            (Settled, Chargeback, true, Err(RejectionReason::NotDisputed)),
+            (
+                Disputed,
+                Dispute,
+                true,
+                Err(RejectionReason::AlreadyDisputed),
+            ),
+            (Disputed, Resolve, true, Ok(Resolved)),
+            (Disputed, Chargeback, true, Ok(ChargedBack)),
+            (Resolved, Dispute, true, Ok(Disputed)),
+            (
+                Resolved,
+                Dispute,
+                false,
+                Err(RejectionReason::AlreadyResolved),
+            ),
+            (Resolved, Resolve, true, Err(RejectionReason::NotDisputed)),
+            (
+                Resolved,
+                Chargeback,
+                true,
+                Err(RejectionReason::NotDisputed),
+            ),
+            (
+                ChargedBack,
+                Dispute,
+                true,
+                Err(RejectionReason::AlreadyChargedBack),
+            ),
+            (
+                ChargedBack,
+                Resolve,
+                true,
+                Err(RejectionReason::AlreadyChargedBack),
+            ),
+            (
+                ChargedBack,
+                Chargeback,
+                true,
+                Err(RejectionReason::AlreadyChargedBack),
+            ),
+        ];
+
+        for (from, action, allow_redispute, expected) in cases {
+            assert_eq!(
+                from.transition(action, allow_redispute),
+                expected,
+                "{:?} + {:?}",
+                from,
+                action
+            );
+        }
+    }
+}
@@ -0,0 +1,904 @@
+use crate::account::*;
+use crate::dispute::*;
+use crate::error::*;
+use crate::invariants::*;
+use crate::journal::*;
+use crate::ledger::*;
+use crate::policy::*;
+use crate::snapshot::*;
+use crate::transaction::*;
+use crate::tx_id_set::*;
+use crate::types::*;
+use crate::validated_transaction::*;
+
+use std::collections::HashMap;
+
+/// The result of successfully applying a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// The client whose account was changed.
+    pub client_id: ClientId,
+    pub applied: ValidatedTransactionCommand,
+    /// How the account's balances changed.
+    pub ledger_entry: LedgerEntry,
+}
+
+/// Synchronous payment engine holding every account and the history needed for disputes.
+#[derive(Default)]
+pub struct Engine {
+    accounts: HashMap<ClientId, Account>,
+    tx_id_to_transaction: HashMap<TxId, TransactionState>,
+    /// Every tx id used by a deposit or withdrawal.
+    seen_tx_ids: TxIdSet,
+    policy: Policy,
+    /// Double-entry books of every command committed, when enabled.
+    trial_balance: Option<TrialBalance>,
+    invariant_checker: Option<InvariantChecker>,
+    /// The first invariant a committed command broke.
+    invariant_violation: Option<InvariantViolation>,
+}
+
+impl Engine {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Resume from the state of an earlier run.
+    pub fn from_snapshot(policy: Policy, snapshot: Snapshot) -> Self {
+        let mut seen_tx_ids = TxIdSet::new();
+        for tx_id in snapshot
+            .transactions
+            .keys()
+            .chain(snapshot.claimed_tx_ids.iter())
+        {
+            seen_tx_ids.insert(*tx_id);
+        }
+
+        Self {
+            accounts: snapshot.accounts.into_iter().collect(),
+            tx_id_to_transaction: snapshot.transactions.into_iter().collect(),
+            seen_tx_ids,
+            policy,
+            trial_balance: None,
+            invariant_checker: None,
+            invariant_violation: None,
+        }
+    }
+
+    /// Keep double-entry books from here on, opened with the current balances. A command whose
+    /// postings don't balance is kept for `invariant_violation`.
+    pub fn with_double_entry(mut self) -> Self {
+        let mut trial_balance = TrialBalance::new();
+        trial_balance.open(&self.accounts);
+        self.trial_balance = Some(trial_balance);
+        self
+    }
+
+    /// Capture all state needed to carry on in a later run.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            accounts: self
+                .accounts
+                .iter()
+                .map(|(client_id, account)| (*client_id, account.clone()))
+                .collect(),
+            transactions: self
+                .tx_id_to_transaction
+                .iter()
+                .map(|(tx_id, transaction)| (*tx_id, transaction.clone()))
+                .collect(),
+            claimed_tx_ids: self
+                .seen_tx_ids
+                .iter()
+                .filter(|tx_id| !self.tx_id_to_transaction.contains_key(tx_id))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Convert, validate and execute a single transaction.
+    pub fn apply(&mut self, tx: AnyTransaction) -> Result<Outcome, RejectionReason> {
+        let tx_command = TransactionCommand::try_from(&tx)?;
+        self.apply_command(&tx_command)
+    }
+
+    /// Validate and execute a single command, recording it once the account has accepted it.
+    pub fn apply_command(
+        &mut self,
+        tx_command: &TransactionCommand,
+    ) -> Result<Outcome, RejectionReason> {
+        match self.check_command(tx_command) {
+            Ok(validated_tx) => self.commit(validated_tx),
+            Err(reason) => {
+                if let Some(tx_id) = Engine::rejected_claim(tx_command, &reason) {
+                    self.claim(tx_id);
+                }
+                Err(reason)
+            }
+        }
+    }
+
+    /// Work out whether a command would be accepted, without changing any state.
+    pub fn check_command(
+        &self,
+        tx_command: &TransactionCommand,
+    ) -> Result<ValidatedTransactionCommand, RejectionReason> {
+        if let Some(tx_id) = claimed_tx_id(tx_command) {
+            if self.seen_tx_ids.contains(tx_id) {
+                return Err(RejectionReason::DuplicateTransaction);
+            }
+        }
+
+        let validated_tx = self.validate_transaction(tx_command)?;
+
+        // Deposits open an account, but only once they are accepted.
+        let mut actioning_account = match self.accounts.get(&validated_tx.client_id()) {
+            Some(account) => account.clone(),
+            None if matches!(validated_tx, ValidatedTransactionCommand::Deposit(_)) => {
+                Account::default()
+            }
+            None => return Err(RejectionReason::UnknownAccount),
+        };
+
+        if actioning_account.locked.is_some() {
+            return Err(RejectionReason::AccountLocked);
+        }
+
+        Engine::execute_and_record(&mut actioning_account, &validated_tx)
+            .map_err(RejectionReason::Account)?;
+
+        Ok(validated_tx)
+    }
+
+    /// Execute and record a command accepted by `check_command`, or replayed from a log of them.
+    pub fn commit(
+        &mut self,
+        validated_tx: ValidatedTransactionCommand,
+    ) -> Result<Outcome, RejectionReason> {
+        let client_id = validated_tx.client_id();
+        let actioning_account = match validated_tx {
+            ValidatedTransactionCommand::Deposit(_) => self.accounts.entry(client_id).or_default(),
+            _ => self
+                .accounts
+                .get_mut(&client_id)
+                .ok_or(RejectionReason::UnknownAccount)?,
+        };
+
+        let before = actioning_account.clone();
+        let ledger_entry = Engine::execute_and_record(actioning_account, &validated_tx)
+            .map_err(RejectionReason::Account)?;
+
+        match &validated_tx {
+            ValidatedTransactionCommand::Deposit(ValidDeposit { tx_id, .. })
+            | ValidatedTransactionCommand::Withdrawal(ValidWithdrawal { tx_id, .. }) => {
+                self.claim(*tx_id);
+            }
+            _ => {}
+        }
+        self.record_transaction(&validated_tx);
+        if let Some(checker) = self.invariant_checker.as_mut() {
+            if let Err(violation) = checker.check(
+                &validated_tx,
+                &before,
+                &self.accounts[&client_id],
+                &self.tx_id_to_transaction[&validated_tx.tx_id()],
+            ) {
+                self.invariant_violation.get_or_insert(*violation);
+            }
+        }
+        if let Some(trial_balance) = self.trial_balance.as_mut() {
+            if let Err(unbalanced) = trial_balance.post(&journal_lines(&validated_tx)) {
+                self.invariant_violation.get_or_insert(InvariantViolation {
+                    invariant: Invariant::PostingBalances,
+                    command: validated_tx.clone(),
+                    after: self.accounts[&client_id].clone(),
+                    before,
+                    expected: MoneySum::default(),
+                    actual: unbalanced.off_by,
+                });
+            }
+        }
+
+        Ok(Outcome {
+            client_id,
+            applied: validated_tx,
+            ledger_entry,
+        })
+    }
+
+    /// Tx ids are claimed by the first deposit or withdrawal to use them, even if it is
+    /// rejected, so that a replay can never be applied. Returns the id a rejected command claims.
+    pub fn rejected_claim(
+        tx_command: &TransactionCommand,
+        reason: &RejectionReason,
+    ) -> Option<TxId> {
+        if *reason == RejectionReason::DuplicateTransaction {
+            return None;
+        }
+        claimed_tx_id(tx_command)
+    }
+
+    /// Mark a tx id as used.
+    pub fn claim(&mut self, tx_id: TxId) {
+        self.seen_tx_ids.insert(tx_id);
+    }
+
+    /// Check the invariants after every command from here on. A violation doesn't stop the
+    /// engine, it is kept for `invariant_violation`.
+    pub fn with_invariant_checks(mut self) -> Self {
+        self.invariant_checker = Some(InvariantChecker::new(
+            &self.accounts,
+            &self.tx_id_to_transaction,
+        ));
+        self
+    }
+
+    pub fn invariant_violation(&self) -> Option<&InvariantViolation> {
+        self.invariant_violation.as_ref()
+    }
+
+    pub fn trial_balance(&self) -> Option<&TrialBalance> {
+        self.trial_balance.as_ref()
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    pub fn accounts(&self) -> &HashMap<ClientId, Account> {
+        &self.accounts
+    }
+
+    pub fn into_accounts(self) -> HashMap<ClientId, Account> {
+        self.accounts
+    }
+
+    fn validate_transaction(
+        &self,
+        tx_command: &TransactionCommand,
+    ) -> Result<ValidatedTransactionCommand, RejectionReason> {
+        match tx_command {
+            TransactionCommand::Deposit(deposit) => {
+                Ok(ValidatedTransactionCommand::Deposit(ValidDeposit {
+                    client_id: deposit.client_id,
+                    tx_id: deposit.tx_id,
+                    amount: deposit.amount,
+                }))
+            }
+            TransactionCommand::Withdrawal(withdrawal) => {
+                let account = self
+                    .accounts
+                    .get(&withdrawal.client_id)
+                    .ok_or(RejectionReason::UnknownAccount)?;
+
+                if account.available < withdrawal.amount {
+                    return Err(RejectionReason::InsufficientFunds);
+                }
+
+                Ok(ValidatedTransactionCommand::Withdrawal(ValidWithdrawal {
+                    client_id: withdrawal.client_id,
+                    tx_id: withdrawal.tx_id,
+                    amount: withdrawal.amount,
+                }))
+            }
+            TransactionCommand::Dispute(dispute) => {
+                let associated_tx = self.find_associated_tx(dispute.client_id, dispute.tx_id)?;
+
+                self.check_transition(associated_tx, DisputeAction::Dispute)?;
+
+                Ok(ValidatedTransactionCommand::Dispute(ValidDispute {
+                    tx_id: dispute.tx_id,
+                    raising_client_id: dispute.client_id,
+                    contended_client_id: associated_tx.client_id,
+                    amount: associated_tx.amount,
+                    hold: self.hold_direction(associated_tx),
+                }))
+            }
+            TransactionCommand::Resolve(resolve) => {
+                let associated_tx = self.find_associated_tx(resolve.client_id, resolve.tx_id)?;
+
+                self.check_transition(associated_tx, DisputeAction::Resolve)?;
+
+                Ok(ValidatedTransactionCommand::Resolve(ValidResolve {
+                    tx_id: resolve.tx_id,
+                    raising_client_id: resolve.client_id,
+                    contended_client_id: associated_tx.client_id,
+                    amount: associated_tx.amount,
+                    hold: self.hold_direction(associated_tx),
+                }))
+            }
+            TransactionCommand::Chargeback(chargeback) => {
+                let associated_tx =
+                    self.find_associated_tx(chargeback.client_id, chargeback.tx_id)?;
+
+                self.check_transition(associated_tx, DisputeAction::Chargeback)?;
+
+                Ok(ValidatedTransactionCommand::Chargeback(ValidChargeback {
+                    tx_id: chargeback.tx_id,
+                    raising_client_id: chargeback.client_id,
+                    contended_client_id: associated_tx.client_id,
+                    amount: associated_tx.amount,
+                    hold: self.hold_direction(associated_tx),
+                    kind: associated_tx.kind,
+                }))
+            }
+        }
+    }
+
+    fn check_transition(
+        &self,
+        associated_tx: &TransactionState,
+        action: DisputeAction,
+    ) -> Result<(), RejectionReason> {
+        associated_tx
+            .dispute_state
+            .transition(action, self.policy.allow_redispute)
+            .map(|_| ())
+    }
+
+    fn hold_direction(&self, associated_tx: &TransactionState) -> HoldDirection {
+        match associated_tx.kind {
+            TransactionKind::Deposit => HoldDirection::FromAvailable,
+            TransactionKind::Withdrawal => self.policy.withdrawal_dispute_hold,
+        }
+    }
+
+    /// Look up the transaction a dispute, resolve or chargeback refers to.
+    /// Only the owning client, or the operator when configured, may act on it.
+    fn find_associated_tx(
+        &self,
+        raising_client_id: ClientId,
+        tx_id: TxId,
+    ) -> Result<&TransactionState, RejectionReason> {
+        let associated_tx = self
+            .tx_id_to_transaction
+            .get(&tx_id)
+            .ok_or(RejectionReason::UnknownTransaction)?;
+
+        let is_owner = associated_tx.client_id == raising_client_id;
+        let is_operator = self.policy.operator_client_id == Some(raising_client_id);
+        if !is_owner && !is_operator {
+            return Err(RejectionReason::ClientMismatch);
+        }
+
+        Ok(associated_tx)
+    }
+
+    /// Execute a command and describe the change it made, leaving the account untouched unless
+    /// both succeed.
+    fn execute_and_record(
+        account: &mut Account,
+        tx_command: &ValidatedTransactionCommand,
+    ) -> Result<LedgerEntry, AccountError> {
+        let mut after = account.clone();
+        Engine::execute_command(&mut after, tx_command)?;
+        let ledger_entry = LedgerEntry::new(tx_command, account, &after)?;
+        *account = after;
+        Ok(ledger_entry)
+    }
+
+    fn execute_command(
+        account: &mut Account,
+        tx_command: &ValidatedTransactionCommand,
+    ) -> Result<(), AccountError> {
+        match tx_command {
+            ValidatedTransactionCommand::Deposit(deposit) => account.deposit(deposit.amount),
+            ValidatedTransactionCommand::Withdrawal(withdrawal) => {
+                account.withdraw(withdrawal.amount)
+            }
+            ValidatedTransactionCommand::Dispute(dispute) => match dispute.hold {
+                HoldDirection::FromAvailable => account.freeze_funds(dispute.amount),
+                HoldDirection::Credit => account.credit_held(dispute.amount),
+            },
+            ValidatedTransactionCommand::Resolve(resolve) => match resolve.hold {
+                HoldDirection::FromAvailable => account.thaw_funds(resolve.amount),
+                HoldDirection::Credit => account.debit_held(resolve.amount),
+            },
+            ValidatedTransactionCommand::Chargeback(chargeback) => {
+                match (chargeback.hold, chargeback.kind) {
+                    (HoldDirection::FromAvailable, TransactionKind::Deposit) => {
+                        account.chargeback(chargeback.amount)
+                    }
+                    (HoldDirection::FromAvailable, TransactionKind::Withdrawal) => {
+                        account.refund_withdrawal(chargeback.amount)
+                    }
+                    (HoldDirection::Credit, _) => account.reverse_withdrawal(chargeback.amount),
+                }
+            }
+        }
+    }
+
+    /// Update the transaction history after a command has been executed successfully.
+    fn record_transaction(&mut self, tx_command: &ValidatedTransactionCommand) {
+        match tx_command {
+            ValidatedTransactionCommand::Deposit(deposit) => {
+                self.tx_id_to_transaction.insert(
+                    deposit.tx_id,
+                    TransactionState {
+                        client_id: deposit.client_id,
+                        kind: TransactionKind::Deposit,
+                        amount: deposit.amount,
+                        dispute_state: DisputeState::Settled,
+                    },
+                );
+            }
+            ValidatedTransactionCommand::Withdrawal(withdrawal) => {
+                self.tx_id_to_transaction.insert(
+                    withdrawal.tx_id,
+                    TransactionState {
+                        client_id: withdrawal.client_id,
+                        kind: TransactionKind::Withdrawal,
+                        amount: withdrawal.amount,
+                        dispute_state: DisputeState::Settled,
+                    },
+                );
+            }
+            ValidatedTransactionCommand::Dispute(dispute) => {
+                self.set_dispute_state(dispute.tx_id, DisputeState::Disputed);
+            }
+            ValidatedTransactionCommand::Resolve(resolve) => {
+                self.set_dispute_state(resolve.tx_id, DisputeState::Resolved);
+            }
+            ValidatedTransactionCommand::Chargeback(chargeback) => {
+                self.set_dispute_state(chargeback.tx_id, DisputeState::ChargedBack);
+            }
+        }
+    }
+
+    fn set_dispute_state(&mut self, tx_id: TxId, dispute_state: DisputeState) {
+        if let Some(tx_state) = self.tx_id_to_transaction.get_mut(&tx_id) {
+            tx_state.dispute_state = dispute_state;
+        }
+    }
+}
+
+/// The tx id a deposit or withdrawal would claim.
+fn claimed_tx_id(tx_command: &TransactionCommand) -> Option<TxId> {
+    match tx_command {
+        TransactionCommand::Deposit(Deposit { tx_id, .. })
+        | TransactionCommand::Withdrawal(Withdrawal { tx_id, .. }) => Some(*tx_id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engine_apply() {
+        let mut engine = Engine::new(Policy::default());
+
+        let outcome = engine
+            .apply(AnyTransaction::new(CommandType::Deposit, 1, 1, "2.5"))
+            .unwrap();
+        assert_eq!(outcome.client_id, 1);
+        assert_eq!(
+            outcome.applied,
+            ValidatedTransactionCommand::Deposit(ValidDeposit {
+                tx_id: 1,
+                client_id: 1,
+                amount: "2.5".parse().unwrap(),
+            })
+        );
+
+        assert_eq!(
+            engine.apply(AnyTransaction::new(CommandType::Withdrawal, 1, 2, "")),
+            Err(RejectionReason::MissingAmount)
+        );
+        assert!(engine
+            .apply(AnyTransaction::new(CommandType::Dispute, 1, 1, ""))
+            .is_ok());
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.held, "2.5".parse().unwrap());
+        assert_eq!(engine.accounts().len(), 1);
+        assert!(engine.account(2).is_none());
+
+        let accounts = engine.into_accounts();
+        assert_eq!(accounts[&1].available, "0".parse().unwrap());
+    }
+
+    #[test]
+    fn test_engine_rejection_reasons() {
+        let mut engine = Engine::new(Policy::default());
+
+        let reason = |engine: &mut Engine, tx_command: TransactionCommand| {
+            engine.apply_command(&tx_command).err()
+        };
+
+        let withdrawal = |tx_id| {
+            TransactionCommand::Withdrawal(Withdrawal {
+                client_id: 1,
+                tx_id,
+                amount: "1.0".parse().unwrap(),
+            })
+        };
+        assert_eq!(
+            reason(&mut engine, withdrawal(1)),
+            Some(RejectionReason::UnknownAccount)
+        );
+
+        let deposit = TransactionCommand::Deposit(Deposit {
+            client_id: 1,
+            tx_id: 2,
+            amount: "0.5".parse().unwrap(),
+        });
+        assert_eq!(reason(&mut engine, deposit), None);
+        assert_eq!(
+            reason(&mut engine, withdrawal(3)),
+            Some(RejectionReason::InsufficientFunds)
+        );
+
+        let dispute = |tx_id| {
+            TransactionCommand::Dispute(Dispute {
+                client_id: 1,
+                tx_id,
+            })
+        };
+        let resolve = TransactionCommand::Resolve(Resolve {
+            client_id: 1,
+            tx_id: 2,
+        });
+        assert_eq!(
+            reason(&mut engine, dispute(3)),
+            Some(RejectionReason::UnknownTransaction)
+        );
+        assert_eq!(
+            reason(&mut engine, resolve.clone()),
+            Some(RejectionReason::NotDisputed)
+        );
+        assert_eq!(reason(&mut engine, dispute(2)), None);
+        assert_eq!(
+            reason(&mut engine, dispute(2)),
+            Some(RejectionReason::AlreadyDisputed)
+        );
+        assert_eq!(reason(&mut engine, resolve), None);
+
+        engine.accounts.get_mut(&1).unwrap().locked = Some(Locked {
+            reason_for_lock: LockReason::Chargeback,
+        });
+        assert_eq!(
+            reason(&mut engine, dispute(2)),
+            Some(RejectionReason::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn test_engine_rejects_cross_client_disputes() {
+        let mut engine = Engine::new(Policy::default());
+
+        let deposit = |client_id, tx_id| {
+            TransactionCommand::Deposit(Deposit {
+                client_id,
+                tx_id,
+                amount: "10.0".parse().unwrap(),
+            })
+        };
+        engine.apply_command(&deposit(1, 1)).unwrap();
+        engine.apply_command(&deposit(2, 2)).unwrap();
+
+        // Client 2 tries to freeze client 1's funds.
+        let attack = [
+            TransactionCommand::Dispute(Dispute {
+                client_id: 2,
+                tx_id: 1,
+            }),
+            TransactionCommand::Resolve(Resolve {
+                client_id: 2,
+                tx_id: 1,
+            }),
+            TransactionCommand::Chargeback(Chargeback {
+                client_id: 2,
+                tx_id: 1,
+            }),
+        ];
+        for tx_command in &attack {
+            assert_eq!(
+                engine.apply_command(tx_command),
+                Err(RejectionReason::ClientMismatch)
+            );
+        }
+
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.available, "10.0".parse().unwrap());
+        assert_eq!(account.held, "0".parse().unwrap());
+
+        // The owner can still dispute their own deposit.
+        engine
+            .apply_command(&TransactionCommand::Dispute(Dispute {
+                client_id: 1,
+                tx_id: 1,
+            }))
+            .unwrap();
+        assert_eq!(
+            engine.accounts.get(&1).unwrap().held,
+            "10.0".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_engine_allows_operator_disputes() {
+        let policy = Policy {
+            operator_client_id: Some(0),
+            ..Default::default()
+        };
+        let mut engine = Engine::new(policy);
+
+        engine
+            .apply_command(&TransactionCommand::Deposit(Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: "10.0".parse().unwrap(),
+            }))
+            .unwrap();
+        engine
+            .apply_command(&TransactionCommand::Dispute(Dispute {
+                client_id: 0,
+                tx_id: 1,
+            }))
+            .unwrap();
+        engine
+            .apply_command(&TransactionCommand::Chargeback(Chargeback {
+                client_id: 0,
+                tx_id: 1,
+            }))
+            .unwrap();
+
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.total(), "0".parse().unwrap());
+        assert!(account.locked.is_some());
+        assert!(!engine.accounts.contains_key(&0));
+    }
+
+    #[test]
+    fn test_engine_rejects_duplicate_tx_ids() {
+        let mut engine = Engine::new(Policy::default());
+
+        let deposit = |tx_id, amount: &str| {
+            TransactionCommand::Deposit(Deposit {
+                client_id: 1,
+                tx_id,
+                amount: amount.parse().unwrap(),
+            })
+        };
+        let withdrawal = |tx_id| {
+            TransactionCommand::Withdrawal(Withdrawal {
+                client_id: 1,
+                tx_id,
+                amount: "1.0".parse().unwrap(),
+            })
+        };
+        let reason = |engine: &mut Engine, tx_command| engine.apply_command(&tx_command).err();
+
+        assert_eq!(reason(&mut engine, deposit(1, "10.0")), None);
+        assert_eq!(reason(&mut engine, withdrawal(2)), None);
+
+        // A rejected withdrawal still claims its id.
+        let overdraw = TransactionCommand::Withdrawal(Withdrawal {
+            client_id: 1,
+            tx_id: 3,
+            amount: "100.0".parse().unwrap(),
+        });
+        assert_eq!(
+            reason(&mut engine, overdraw),
+            Some(RejectionReason::InsufficientFunds)
+        );
+        assert_eq!(
+            reason(&mut engine, deposit(3, "1.0")),
+            Some(RejectionReason::DuplicateTransaction)
+        );
+
+        // Replays of either kind, or reuse of an id across kinds, are rejected.
+        for tx_command in [
+            deposit(1, "5.0"),
+            deposit(2, "5.0"),
+            withdrawal(1),
+            withdrawal(2),
+        ] {
+            assert_eq!(
+                reason(&mut engine, tx_command),
+                Some(RejectionReason::DuplicateTransaction)
+            );
+        }
+
+        // The original deposit is still disputable for its original amount.
+        assert_eq!(
+            reason(
+                &mut engine,
+                TransactionCommand::Dispute(Dispute {
+                    client_id: 1,
+                    tx_id: 1,
+                })
+            ),
+            None
+        );
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.available, "-1.0".parse().unwrap());
+        assert_eq!(account.held, "10.0".parse().unwrap());
+    }
+
+    #[test]
+    fn test_engine_withdrawal_disputes() {
+        let run = |hold, last: TransactionCommand| {
+            let policy = Policy {
+                withdrawal_dispute_hold: hold,
+                ..Default::default()
+            };
+            let mut engine = Engine::new(policy)
+                .with_double_entry()
+                .with_invariant_checks();
+
+            let commands = [
+                TransactionCommand::Deposit(Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: "10.0".parse().unwrap(),
+                }),
+                TransactionCommand::Withdrawal(Withdrawal {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: "4.0".parse().unwrap(),
+                }),
+                TransactionCommand::Dispute(Dispute {
+                    client_id: 1,
+                    tx_id: 2,
+                }),
+                last,
+            ];
+            for tx_command in &commands {
+                engine.apply_command(tx_command).unwrap();
+            }
+            // The books and the invariants agree with every way of settling the dispute.
+            engine
+                .trial_balance()
+                .unwrap()
+                .check(engine.accounts())
+                .unwrap();
+            assert_eq!(engine.invariant_violation(), None);
+
+            let account = engine.accounts.remove(&1).unwrap();
+            (
+                account.available.to_string(),
+                account.held.to_string(),
+                account.locked.is_some(),
+            )
+        };
+        let resolve = || {
+            TransactionCommand::Resolve(Resolve {
+                client_id: 1,
+                tx_id: 2,
+            })
+        };
+        let chargeback = || {
+            TransactionCommand::Chargeback(Chargeback {
+                client_id: 1,
+                tx_id: 2,
+            })
+        };
+        let no_op = || {
+            TransactionCommand::Deposit(Deposit {
+                client_id: 1,
+                tx_id: 3,
+                amount: "0".parse().unwrap(),
+            })
+        };
+
+        // Disputed withdrawal credited into held, a zero deposit leaves the dispute open.
+        assert_eq!(
+            run(HoldDirection::Credit, no_op()),
+            ("6.0000".to_string(), "4.0000".to_string(), false)
+        );
+        assert_eq!(
+            run(HoldDirection::Credit, resolve()),
+            ("6.0000".to_string(), "0.0000".to_string(), false)
+        );
+        assert_eq!(
+            run(HoldDirection::Credit, chargeback()),
+            ("10.0000".to_string(), "0.0000".to_string(), true)
+        );
+
+        // Disputed withdrawal held from available, like a deposit.
+        assert_eq!(
+            run(HoldDirection::FromAvailable, no_op()),
+            ("2.0000".to_string(), "4.0000".to_string(), false)
+        );
+        assert_eq!(
+            run(HoldDirection::FromAvailable, resolve()),
+            ("6.0000".to_string(), "0.0000".to_string(), false)
+        );
+        // Charging back the withdrawal releases the hold and returns the withdrawn funds.
+        assert_eq!(
+            run(HoldDirection::FromAvailable, chargeback()),
+            ("10.0000".to_string(), "0.0000".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn test_engine_rejects_total_overflow() {
+        // Crediting the disputed withdrawal to held would take the total past a `Money`.
+        let mut engine = Engine::new(Policy::default());
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "900000000000000"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 2, "500000000000000"),
+            AnyTransaction::new(CommandType::Deposit, 1, 3, "500000000000000"),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+        let before = engine.account(1).unwrap().clone();
+
+        assert_eq!(
+            engine.apply(AnyTransaction::new(CommandType::Dispute, 1, 2, "")),
+            Err(RejectionReason::Account(AccountError::Overflow))
+        );
+        assert_eq!(engine.account(1), Some(&before));
+        assert_eq!(before.total().to_string(), "900000000000000.0000");
+    }
+
+    #[test]
+    fn test_engine_dispute_lifecycle_is_enforced() {
+        let run = |allow_redispute| {
+            let policy = Policy {
+                allow_redispute,
+                operator_client_id: Some(0),
+                ..Default::default()
+            };
+            let mut engine = Engine::new(policy);
+
+            let dispute = TransactionCommand::Dispute(Dispute {
+                client_id: 0,
+                tx_id: 1,
+            });
+            let commands = [
+                TransactionCommand::Deposit(Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: "10.0".parse().unwrap(),
+                }),
+                dispute.clone(),
+                TransactionCommand::Resolve(Resolve {
+                    client_id: 0,
+                    tx_id: 1,
+                }),
+                dispute.clone(),
+                TransactionCommand::Chargeback(Chargeback {
+                    client_id: 0,
+                    tx_id: 1,
+                }),
+                dispute,
+            ];
+
+            let reasons: Vec<Option<RejectionReason>> = commands
+                .iter()
+                .map(|tx_command| engine.apply_command(tx_command).err())
+                .collect();
+            reasons
+        };
+
+        assert_eq!(
+            run(true),
+            vec![
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(RejectionReason::AlreadyChargedBack)
+            ]
+        );
+        assert_eq!(
+            run(false),
+            vec![
+                None,
+                None,
+                None,
+                Some(RejectionReason::AlreadyResolved),
+                Some(RejectionReason::NotDisputed),
+                Some(RejectionReason::AlreadyResolved)
+            ]
+        );
+    }
+}
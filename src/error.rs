@@ -0,0 +1,203 @@
+use crate::account::AccountError;
+use crate::transaction::*;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Why a transaction was not applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// The row could not be deserialized into a transaction.
+    MalformedRow,
+    /// A deposit or withdrawal was missing its amount.
+    MissingAmount,
+    /// The `type` column did not match any known command.
+    UnknownCommandType,
+    /// A deposit or withdrawal reused the tx id of an earlier one.
+    DuplicateTransaction,
+    /// A withdrawal exceeded the available funds.
+    InsufficientFunds,
+    /// The client has no account to act upon.
+    UnknownAccount,
+    /// A dispute, resolve or chargeback referenced a tx that does not exist.
+    UnknownTransaction,
+    /// A dispute, resolve or chargeback was raised by a client that does not own the tx.
+    ClientMismatch,
+    /// A dispute referenced a tx that is already under dispute.
+    AlreadyDisputed,
+    /// A resolve or chargeback referenced a tx that is not under dispute.
+    NotDisputed,
+    /// A dispute referenced a resolved tx and re-disputes are not allowed.
+    AlreadyResolved,
+    /// The referenced tx was charged back and can no longer be acted upon.
+    AlreadyChargedBack,
+    /// The account is locked and no longer accepts commands.
+    AccountLocked,
+    /// The account refused the balance change.
+    Account(AccountError),
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectionReason::MalformedRow => write!(f, "row could not be parsed"),
+            RejectionReason::MissingAmount => write!(f, "amount is missing"),
+            RejectionReason::UnknownCommandType => write!(f, "unknown transaction type"),
+            RejectionReason::DuplicateTransaction => write!(f, "transaction id was already used"),
+            RejectionReason::InsufficientFunds => write!(f, "not enough available funds"),
+            RejectionReason::UnknownAccount => write!(f, "account not found"),
+            RejectionReason::UnknownTransaction => write!(f, "associated transaction not found"),
+            RejectionReason::ClientMismatch => {
+                write!(f, "transaction belongs to a different client")
+            }
+            RejectionReason::AlreadyDisputed => write!(f, "transaction is already under dispute"),
+            RejectionReason::NotDisputed => write!(f, "transaction is not under dispute"),
+            RejectionReason::AlreadyResolved => {
+                write!(f, "transaction dispute was already resolved")
+            }
+            RejectionReason::AlreadyChargedBack => {
+                write!(f, "transaction was already charged back")
+            }
+            RejectionReason::AccountLocked => write!(f, "account is locked"),
+            RejectionReason::Account(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl RejectionReason {
+    /// A stable, machine-readable identifier for the reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectionReason::MalformedRow => "malformed_row",
+            RejectionReason::MissingAmount => "missing_amount",
+            RejectionReason::UnknownCommandType => "unknown_command_type",
+            RejectionReason::DuplicateTransaction => "duplicate_transaction",
+            RejectionReason::InsufficientFunds => "insufficient_funds",
+            RejectionReason::UnknownAccount => "unknown_account",
+            RejectionReason::UnknownTransaction => "unknown_transaction",
+            RejectionReason::ClientMismatch => "client_mismatch",
+            RejectionReason::AlreadyDisputed => "already_disputed",
+            RejectionReason::NotDisputed => "not_disputed",
+            RejectionReason::AlreadyResolved => "already_resolved",
+            RejectionReason::AlreadyChargedBack => "already_charged_back",
+            RejectionReason::AccountLocked => "account_locked",
+            RejectionReason::Account(AccountError::NegativeAmount(_)) => "negative_amount",
+            RejectionReason::Account(AccountError::Overflow) => "balance_overflow",
+            RejectionReason::Account(AccountError::InsufficientHeldFunds { .. }) => {
+                "insufficient_held_funds"
+            }
+        }
+    }
+}
+
+/// A rejected transaction along with the reason it was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineError {
+    pub reason: RejectionReason,
+    pub transaction: AnyTransaction,
+}
+
+impl EngineError {
+    pub fn new(reason: RejectionReason, transaction: impl Into<AnyTransaction>) -> Self {
+        Self {
+            reason,
+            transaction: transaction.into(),
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.reason, self.transaction)
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// The pipeline stage that rejected a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Reader,
+    CommandConverter,
+    Router,
+    AccountManager,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Reader => "reader",
+            Stage::CommandConverter => "command_converter",
+            Stage::Router => "router",
+            Stage::AccountManager => "account_manager",
+        }
+    }
+}
+
+/// What a stage did with its input, returned once the stage has drained its channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageSummary {
+    pub stage: Stage,
+    /// Rows passed on to the next stage, or applied for the account manager.
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+impl StageSummary {
+    pub fn new(stage: Stage) -> Self {
+        Self {
+            stage,
+            accepted: 0,
+            rejected: 0,
+        }
+    }
+}
+
+/// The fields of a rejected row, as close to the original input as the stage still knows them.
+/// The reader keeps the raw text, while the later stages only have the parsed transaction and
+/// report it normalised.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RejectedRecord {
+    pub command_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+}
+
+/// Lowercases the type and writes the amount with four decimal places, e.g. `Deposit,5.0`
+/// comes out as `deposit,5.0000`.
+impl From<&AnyTransaction> for RejectedRecord {
+    fn from(tx: &AnyTransaction) -> Self {
+        Self {
+            command_type: tx.command_type.as_str().to_string(),
+            client: tx.client_id.to_string(),
+            tx: tx.tx_id.to_string(),
+            amount: tx.amount.map(|a| a.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Sent by every stage to the rejection sink.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub file: Arc<str>,
+    pub line: u64,
+    pub stage: Stage,
+    pub reason: RejectionReason,
+    pub record: RejectedRecord,
+    /// Human readable description of the failure.
+    pub detail: String,
+}
+
+impl Rejection {
+    pub fn from_engine_error<T>(stage: Stage, sourced: &Sourced<T>, e: &EngineError) -> Self {
+        Self {
+            file: sourced.file.clone(),
+            line: sourced.line,
+            stage,
+            reason: e.reason.clone(),
+            record: RejectedRecord::from(&e.transaction),
+            detail: e.reason.to_string(),
+        }
+    }
+}
@@ -1,252 +1,249 @@
-use crate::account::*;
+use crate::engine::*;
+use crate::error::*;
+use crate::ledger::*;
 use crate::transaction::*;
 use crate::types::*;
-use crate::validated_transaction::*;
+use crate::wal::*;
+
+use eyre::Result;
+use std::{
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    thread::JoinHandle,
+};
+
+/// The input row whose deposit opened a client's account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountOpening {
+    pub client_id: ClientId,
+    pub file: Arc<str>,
+    pub line: u64,
+}
 
-use eyre::*;
-use std::result::Result::Ok;
-use std::{collections::HashMap, sync::mpsc::Receiver, thread, thread::JoinHandle};
+/// What a shard leaves behind once its input is exhausted.
+pub struct ShardOutput {
+    pub summary: StageSummary,
+    pub engine: Engine,
+    /// Accounts opened by the shard, in input order.
+    pub openings: Vec<AccountOpening>,
+}
 
+/// Drives an `Engine` from a channel of commands on its own thread.
 pub struct AccountManager {
-    accounts: HashMap<ClientId, Account>,
-    tx_id_to_deposit: HashMap<TxId, DepositState>,
-    rx: Receiver<TransactionCommand>,
+    engine: Engine,
+    rx: Receiver<Sourced<TransactionCommand>>,
+    rejections: SyncSender<Rejection>,
+    wal: Option<Wal>,
+    ledger: Option<SyncSender<LedgerEntry>>,
 }
 
 impl AccountManager {
-    pub fn new(rx: Receiver<TransactionCommand>) -> Self {
+    /// The engine may be fresh or resumed from a snapshot.
+    pub fn new(
+        rx: Receiver<Sourced<TransactionCommand>>,
+        rejections: SyncSender<Rejection>,
+        engine: Engine,
+    ) -> Self {
         Self {
-            accounts: HashMap::new(),
-            tx_id_to_deposit: HashMap::new(),
+            engine,
             rx,
+            rejections,
+            wal: None,
+            ledger: None,
         }
     }
 
-    pub fn start(mut self) -> JoinHandle<HashMap<ClientId, Account>> {
+    /// Send every balance change to `ledger`.
+    pub fn with_ledger(mut self, ledger: SyncSender<LedgerEntry>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Log every change to `wal` before making it. The engine must already hold the state the
+    /// log was replayed into.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn start(mut self) -> JoinHandle<Result<ShardOutput>> {
         thread::spawn(move || {
-            loop {
-                match self.rx.recv() {
-                    Ok(tx_command) => {
-                        // Insert deposits into tx_id_to_deposit
-                        if let TransactionCommand::Deposit(deposit) = &tx_command {
-                            self.tx_id_to_deposit.insert(
-                                deposit.tx_id,
-                                DepositState {
-                                    client_id: deposit.client_id,
-                                    amount: deposit.amount,
-                                    is_under_dispute: false,
-                                },
-                            );
+            let mut summary = StageSummary::new(Stage::AccountManager);
+            let mut openings = Vec::new();
+            while let Ok(sourced) = self.rx.recv() {
+                let opens_account = matches!(&sourced.value,
+                    TransactionCommand::Deposit(deposit) if self.engine.account(deposit.client_id).is_none());
+                match self.apply(&sourced)? {
+                    Ok(outcome) => {
+                        if let Some(violation) = self.engine.invariant_violation() {
+                            return Err(eyre::Report::new(violation.clone()).wrap_err(format!(
+                                "Invariant broken by {}:{}",
+                                sourced.file, sourced.line
+                            )));
                         }
-
-                        match self.validate_transaction(&tx_command) {
-                            Ok(validated_tx) => {
-                                if let Some(actioning_account) =
-                                    self.find_actioning_account(&validated_tx)
-                                {
-                                    if actioning_account.locked.is_none() {
-                                        // Execute the command
-                                        AccountManager::execute_command(
-                                            actioning_account,
-                                            &validated_tx,
-                                        );
-                                    } else {
-                                        eprintln!(
-                                            "Cannot action on a locked account: {:?}",
-                                            actioning_account
-                                        );
-                                    }
-                                } else {
-                                    eprintln!(
-                                        "Cannot find actioning account for transaction: {:?}",
-                                        validated_tx
-                                    );
-                                    continue;
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to validate transaction: {:?}", e);
-                                continue;
-                            }
+                        summary.accepted += 1;
+                        if opens_account {
+                            openings.push(AccountOpening {
+                                client_id: outcome.client_id,
+                                file: sourced.file.clone(),
+                                line: sourced.line,
+                            });
+                        }
+                        if let Some(ledger) = &self.ledger {
+                            let _ = ledger.send(outcome.ledger_entry);
                         }
                     }
-                    Err(_) => break, // Receiver has been dropped
-                }
-            }
-            self.accounts
-        })
-    }
-
-    fn validate_transaction(
-        &mut self,
-        tx_command: &TransactionCommand,
-    ) -> Result<ValidatedTransactionCommand> {
-        match tx_command {
-            TransactionCommand::Deposit(deposit) => {
-                Ok(ValidatedTransactionCommand::Deposit(ValidDeposit {
-                    client_id: deposit.client_id,
-                    tx_id: deposit.tx_id,
-                    amount: deposit.amount,
-                }))
-            }
-            TransactionCommand::Withdrawal(withdrawal) => {
-                // Access the account immutably for validation
-                if let Some(account) = self.accounts.get(&withdrawal.client_id) {
-                    if account.available >= withdrawal.amount {
-                        Ok(ValidatedTransactionCommand::Withdrawal(ValidWithdrawal {
-                            client_id: withdrawal.client_id,
-                            tx_id: withdrawal.tx_id,
-                            amount: withdrawal.amount,
-                        }))
-                    } else {
-                        Err(eyre!("Cannot withdraw, not enough funds. {:?}", withdrawal))
+                    Err(reason) => {
+                        summary.rejected += 1;
+                        let _ = self.rejections.send(Rejection::from_engine_error(
+                            Stage::AccountManager,
+                            &sourced,
+                            &EngineError::new(reason, &sourced.value),
+                        ));
                     }
-                } else {
-                    Err(eyre!("Account not found for withdrawal: {:?}", withdrawal))
-                }
-            }
-            TransactionCommand::Dispute(dispute) => {
-                let associated_tx = self
-                    .tx_id_to_deposit
-                    .get_mut(&dispute.tx_id)
-                    .ok_or_else(|| eyre!("Unable to find associated transaction for dispute"))?;
-
-                if associated_tx.is_under_dispute {
-                    return Err(eyre!("Transaction is already under dispute: {:?}", dispute));
-                }
-
-                associated_tx.is_under_dispute = true;
-
-                Ok(ValidatedTransactionCommand::Dispute(ValidDispute {
-                    tx_id: dispute.tx_id,
-                    raising_client_id: dispute.client_id,
-                    contended_client_id: associated_tx.client_id,
-                    amount: associated_tx.amount,
-                }))
-            }
-            TransactionCommand::Resolve(resolve) => {
-                let associated_tx = self
-                    .tx_id_to_deposit
-                    .get_mut(&resolve.tx_id)
-                    .ok_or_else(|| eyre!("Unable to find associated transaction for resolve"))?;
-
-                if !associated_tx.is_under_dispute {
-                    return Err(eyre!("Transaction is not under dispute: {:?}", resolve));
                 }
-
-                associated_tx.is_under_dispute = false;
-
-                Ok(ValidatedTransactionCommand::Resolve(ValidResolve {
-                    tx_id: resolve.tx_id,
-                    raising_client_id: resolve.client_id,
-                    contended_client_id: associated_tx.client_id,
-                    amount: associated_tx.amount,
-                }))
             }
-            TransactionCommand::Chargeback(chargeback) => {
-                let associated_tx = self
-                    .tx_id_to_deposit
-                    .get_mut(&chargeback.tx_id)
-                    .ok_or_else(|| eyre!("Unable to find associated transaction for chargeback"))?;
-
-                if !associated_tx.is_under_dispute {
-                    return Err(eyre!("Transaction is not under dispute: {:?}", chargeback));
-                }
-
-                associated_tx.is_under_dispute = false;
-
-                Ok(ValidatedTransactionCommand::Chargeback(ValidChargeback {
-                    tx_id: chargeback.tx_id,
-                    raising_client_id: chargeback.client_id,
-                    contended_client_id: associated_tx.client_id,
-                    amount: associated_tx.amount,
-                }))
+            if let Some(wal) = self.wal.as_mut() {
+                wal.sync()?;
             }
-        }
+            Ok(ShardOutput {
+                summary,
+                engine: self.engine,
+                openings,
+            })
+        })
     }
 
-    fn find_actioning_account(
+    /// The outer error is a failure to write the log, which stops the shard.
+    fn apply(
         &mut self,
-        tx_command: &ValidatedTransactionCommand,
-    ) -> Option<&mut Account> {
-        match tx_command {
-            ValidatedTransactionCommand::Deposit(deposit) => Some(
-                self.accounts
-                    .entry(deposit.client_id)
-                    .or_insert(Default::default()),
-            ),
-            ValidatedTransactionCommand::Withdrawal(withdrawal) => {
-                self.accounts.get_mut(&withdrawal.client_id)
+        sourced: &Sourced<TransactionCommand>,
+    ) -> Result<std::result::Result<Outcome, RejectionReason>> {
+        let Some(wal) = self.wal.as_mut() else {
+            return Ok(self.engine.apply_command(&sourced.value));
+        };
+        let mut log = |record| {
+            wal.append(&WalEntry {
+                file: sourced.file.to_string(),
+                line: sourced.line,
+                record,
+            })
+        };
+
+        Ok(match self.engine.check_command(&sourced.value) {
+            Ok(validated_tx) => {
+                log(WalRecord::Applied(validated_tx.clone()))?;
+                self.engine.commit(validated_tx)
             }
-            ValidatedTransactionCommand::Dispute(dispute) => {
-                self.accounts.get_mut(&dispute.contended_client_id)
-            }
-            ValidatedTransactionCommand::Resolve(resolve) => {
-                self.accounts.get_mut(&resolve.contended_client_id)
-            }
-            ValidatedTransactionCommand::Chargeback(chargeback) => {
-                self.accounts.get_mut(&chargeback.contended_client_id)
-            }
-        }
-    }
-
-    fn execute_command(account: &mut Account, tx_command: &ValidatedTransactionCommand) {
-        match tx_command {
-            ValidatedTransactionCommand::Deposit(deposit) => {
-                account.deposit(deposit.amount);
-            }
-            ValidatedTransactionCommand::Withdrawal(withdrawal) => {
-                account.withdraw(withdrawal.amount);
-            }
-            ValidatedTransactionCommand::Dispute(dispute) => {
-                account.freeze_funds(dispute.amount);
-            }
-            ValidatedTransactionCommand::Resolve(resolve) => {
-                account.thaw_funds(resolve.amount);
-            }
-            ValidatedTransactionCommand::Chargeback(chargeback) => {
-                account.chargeback(chargeback.amount);
+            Err(reason) => {
+                if let Some(tx_id) = Engine::rejected_claim(&sourced.value, &reason) {
+                    log(WalRecord::Claimed(tx_id))?;
+                    self.engine.claim(tx_id);
+                }
+                Err(reason)
             }
-        }
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::mpsc::channel;
+    use crate::policy::*;
+    use std::sync::mpsc::sync_channel;
 
     #[test]
     fn test_account_manager() {
-        let (tx_tx_command, rx_tx_command) = channel();
+        let (tx_tx_command, rx_tx_command) = sync_channel(16);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
 
-        let account_manager = AccountManager::new(rx_tx_command);
+        let account_manager =
+            AccountManager::new(rx_tx_command, tx_rejection, Engine::new(Policy::default()));
         let handle = account_manager.start();
 
         tx_tx_command
-            .send(TransactionCommand::Deposit(Deposit {
-                client_id: 1,
-                tx_id: 1,
-                amount: 1.0,
-            }))
+            .send(Sourced::new(
+                "test.csv".into(),
+                1,
+                TransactionCommand::Deposit(Deposit {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: "1.0".parse().unwrap(),
+                }),
+            ))
             .unwrap();
 
         tx_tx_command
-            .send(TransactionCommand::Withdrawal(Withdrawal {
-                client_id: 1,
-                tx_id: 2,
-                amount: 0.5,
-            }))
+            .send(Sourced::new(
+                "test.csv".into(),
+                1,
+                TransactionCommand::Withdrawal(Withdrawal {
+                    client_id: 1,
+                    tx_id: 2,
+                    amount: "0.5".parse().unwrap(),
+                }),
+            ))
             .unwrap();
 
         drop(tx_tx_command);
 
-        let accounts = handle.join().unwrap();
-
-        let account = accounts.get(&1).expect("Account not found");
-        assert_eq!(account.available, 0.5);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total(), 0.5);
+        let ShardOutput {
+            summary,
+            engine,
+            openings,
+        } = handle.join().unwrap().unwrap();
+        assert_eq!(summary.accepted, 2);
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(
+            openings,
+            vec![AccountOpening {
+                client_id: 1,
+                file: "test.csv".into(),
+                line: 1,
+            }]
+        );
+
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, "0.5".parse().unwrap());
+        assert_eq!(account.held, "0.0".parse().unwrap());
+        assert_eq!(account.total(), "0.5".parse().unwrap());
         assert!(account.locked.is_none());
     }
+
+    #[test]
+    fn test_account_manager_rejects_overflowing_deposit() {
+        let (tx_tx_command, rx_tx_command) = sync_channel(16);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
+
+        let account_manager =
+            AccountManager::new(rx_tx_command, tx_rejection, Engine::new(Policy::default()));
+        let handle = account_manager.start();
+
+        for tx_id in 1..=2 {
+            tx_tx_command
+                .send(Sourced::new(
+                    "test.csv".into(),
+                    1,
+                    TransactionCommand::Deposit(Deposit {
+                        client_id: 1,
+                        tx_id,
+                        amount: "922337203685477".parse().unwrap(),
+                    }),
+                ))
+                .unwrap();
+        }
+
+        drop(tx_tx_command);
+
+        let ShardOutput {
+            summary, engine, ..
+        } = handle.join().unwrap().unwrap();
+        assert_eq!(summary.rejected, 1);
+
+        let account = engine.account(1).expect("Account not found");
+        assert_eq!(account.available, "922337203685477".parse().unwrap());
+    }
 }
@@ -1,9 +1,9 @@
+use crate::error::*;
 use crate::transaction::*;
 
-use eyre::*;
-use std::result::Result::Ok;
+use eyre::Result;
 use std::{
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{Receiver, SyncSender},
     thread,
     thread::JoinHandle,
 };
@@ -11,124 +11,97 @@ use std::{
 /// Try and convert the AnyTransaction into a transacton command.
 /// Valdation over required fields is done here.
 pub struct CommandConverter {
-    tx: Sender<TransactionCommand>,
-    rx: Receiver<AnyTransaction>,
+    tx: SyncSender<Sourced<TransactionCommand>>,
+    rx: Receiver<Sourced<AnyTransaction>>,
+    rejections: SyncSender<Rejection>,
 }
 
 impl CommandConverter {
-    pub fn new(rx: Receiver<AnyTransaction>, tx: Sender<TransactionCommand>) -> Self {
-        Self { tx, rx }
+    pub fn new(
+        rx: Receiver<Sourced<AnyTransaction>>,
+        tx: SyncSender<Sourced<TransactionCommand>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Self {
+        Self { tx, rx, rejections }
     }
 
-    pub fn start(self) -> JoinHandle<()> {
+    pub fn start(self) -> JoinHandle<Result<StageSummary>> {
         thread::spawn(move || {
-            loop {
-                match self.rx.recv() {
-                    Ok(tx) => {
-                        let maybe_tx_command = match tx.command_type {
-                            CommandType::Deposit => {
-                                if let Some(amount) = tx.amount {
-                                    Ok(TransactionCommand::Deposit(Deposit {
-                                        client_id: tx.client_id,
-                                        tx_id: tx.tx_id,
-                                        amount,
-                                    }))
-                                } else {
-                                    Err(eyre!(
-                                        "Found erroneous deposit transaction, ignoring: {:?}",
-                                        tx
-                                    ))
-                                }
-                            }
-                            CommandType::Withdrawal => {
-                                if let Some(amount) = tx.amount {
-                                    Ok(TransactionCommand::Withdrawal(Withdrawal {
-                                        client_id: tx.client_id,
-                                        tx_id: tx.tx_id,
-                                        amount,
-                                    }))
-                                } else {
-                                    Err(eyre!(
-                                        "Found erroneous withdrawal transaction, ignoring: {:?}",
-                                        tx
-                                    ))
-                                }
-                            }
-                            CommandType::Dispute => Ok(TransactionCommand::Dispute(Dispute {
-                                client_id: tx.client_id,
-                                tx_id: tx.tx_id,
-                            })),
-                            CommandType::Resolve => Ok(TransactionCommand::Resolve(Resolve {
-                                client_id: tx.client_id,
-                                tx_id: tx.tx_id,
-                            })),
-                            CommandType::Chargeback => {
-                                Ok(TransactionCommand::Chargeback(Chargeback {
-                                    client_id: tx.client_id,
-                                    tx_id: tx.tx_id,
-                                }))
-                            }
-                            CommandType::Unknown => {
-                                Err(eyre!("Found unknown transaction, ignoring: {:?}", tx))
-                            }
-                        };
-
-                        match maybe_tx_command {
-                            Ok(tx_command) => {
-                                if self.tx.send(tx_command).is_err() {
-                                    break; // Receiver has been dropped
-                                }
-                            }
-                            Err(e) => eprintln!(
-                                "Failed to convert AnyTransaction into TransactionCommand:\n{:?}",
-                                e
-                            ),
+            let mut summary = StageSummary::new(Stage::CommandConverter);
+            while let Ok(sourced) = self.rx.recv() {
+                match CommandConverter::convert(&sourced.value) {
+                    Ok(tx_command) => {
+                        summary.accepted += 1;
+                        if self.tx.send(sourced.map(|_| tx_command)).is_err() {
+                            break; // Receiver has been dropped
                         }
                     }
-                    Err(_) => break, // Receiver has been dropped
+                    Err(e) => {
+                        summary.rejected += 1;
+                        let _ = self.rejections.send(Rejection::from_engine_error(
+                            Stage::CommandConverter,
+                            &sourced,
+                            &e,
+                        ));
+                    }
                 }
             }
             drop(self.tx);
+            Ok(summary)
         })
     }
+
+    fn convert(tx: &AnyTransaction) -> Result<TransactionCommand, EngineError> {
+        TransactionCommand::try_from(tx).map_err(|reason| EngineError::new(reason, tx.clone()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::mpsc::channel;
+    use std::sync::mpsc::sync_channel;
 
     #[test]
     fn test_command_converter() {
-        let (tx_any_tx, rx_any_tx) = channel();
-        let (tx_tx_command, rx_tx_command) = channel();
+        let (tx_any_tx, rx_any_tx) = sync_channel(16);
+        let (tx_tx_command, rx_tx_command) = sync_channel(16);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
 
-        let command_converter = CommandConverter::new(rx_any_tx, tx_tx_command);
+        let command_converter = CommandConverter::new(rx_any_tx, tx_tx_command, tx_rejection);
         let handle = command_converter.start();
 
         tx_any_tx
-            .send(AnyTransaction {
-                command_type: CommandType::Deposit,
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(1.0),
-            })
+            .send(Sourced::new(
+                "test.csv".into(),
+                1,
+                AnyTransaction {
+                    command_type: CommandType::Deposit,
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: Some("1.0".parse().unwrap()),
+                },
+            ))
             .unwrap();
 
         tx_any_tx
-            .send(AnyTransaction {
-                command_type: CommandType::Withdrawal,
-                client_id: 2,
-                tx_id: 2,
-                amount: Some(2.0),
-            })
+            .send(Sourced::new(
+                "test.csv".into(),
+                1,
+                AnyTransaction {
+                    command_type: CommandType::Withdrawal,
+                    client_id: 2,
+                    tx_id: 2,
+                    amount: Some("2.0".parse().unwrap()),
+                },
+            ))
             .unwrap();
 
         drop(tx_any_tx);
 
-        handle.join().unwrap();
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!(summary.accepted, 2);
 
-        let commands: Vec<TransactionCommand> = rx_tx_command.iter().collect();
+        let commands: Vec<TransactionCommand> = rx_tx_command.iter().map(|s| s.value).collect();
 
         assert_eq!(commands.len(), 2);
 
@@ -136,7 +109,7 @@ mod tests {
             TransactionCommand::Deposit(deposit) => {
                 assert_eq!(deposit.client_id, 1);
                 assert_eq!(deposit.tx_id, 1);
-                assert_eq!(deposit.amount, 1.0);
+                assert_eq!(deposit.amount, "1.0".parse().unwrap());
             }
             _ => panic!("Expected Deposit command"),
         }
@@ -145,9 +118,30 @@ mod tests {
             TransactionCommand::Withdrawal(withdrawal) => {
                 assert_eq!(withdrawal.client_id, 2);
                 assert_eq!(withdrawal.tx_id, 2);
-                assert_eq!(withdrawal.amount, 2.0);
+                assert_eq!(withdrawal.amount, "2.0".parse().unwrap());
             }
             _ => panic!("Expected Withdrawal command"),
         }
     }
+
+    #[test]
+    fn test_command_converter_rejections() {
+        let missing_amount = AnyTransaction::new(CommandType::Withdrawal, 1, 1, "");
+        let unknown = AnyTransaction::new(CommandType::Unknown, 1, 2, "");
+
+        assert_eq!(
+            CommandConverter::convert(&missing_amount),
+            Err(EngineError::new(
+                RejectionReason::MissingAmount,
+                missing_amount
+            ))
+        );
+        assert_eq!(
+            CommandConverter::convert(&unknown),
+            Err(EngineError::new(
+                RejectionReason::UnknownCommandType,
+                unknown
+            ))
+        );
+    }
 }
@@ -1,48 +1,363 @@
+use super::input::*;
+use crate::error::*;
+use crate::handlers::join_thread;
 use crate::transaction::*;
 
-use csv::Reader;
+use csv::{ReaderBuilder, StringRecord, Trim};
 use eyre::*;
 use std::result::Result::Ok;
-use std::{fs::File, sync::mpsc::Sender, thread};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Size of the byte ranges handed to parser threads when reading in parallel.
+const CHUNK_SIZE: u64 = 1 << 20;
 
 /// Used for reading line by line and deserializing.
 pub struct CsvReader {
-    tx: Sender<AnyTransaction>,
+    rows: RowSender,
+}
+
+struct ParsedChunk {
+    rows: Vec<ParsedRow>,
+    /// Number of input lines the chunk spans.
+    lines: u64,
 }
 
 impl CsvReader {
-    pub fn new(tx: Sender<AnyTransaction>) -> Self {
-        Self { tx }
+    pub fn new(tx: SyncSender<Sourced<AnyTransaction>>, rejections: SyncSender<Rejection>) -> Self {
+        Self {
+            rows: RowSender::new(tx, rejections),
+        }
     }
 
-    // Log and ignore erroneous lines.
-    pub fn start(self, file_name: String, _thread_count: u8) -> Result<thread::JoinHandle<()>> {
+    /// Report and ignore erroneous lines.
+    ///
+    /// Inputs are read one after the other as a single stream, each with its own header row.
+    ///
+    /// With more than one thread each file is split into byte ranges aligned on line boundaries
+    /// which are parsed concurrently, then re-sequenced so rows are sent in their original order.
+    /// This assumes no quoted field spans several lines. Stdin is always read on one thread.
+    pub fn start(
+        self,
+        file_names: Vec<String>,
+        thread_count: u8,
+    ) -> Result<JoinHandle<Result<StageSummary>>> {
+        let inputs = open_inputs(file_names)?;
+
         let handle = thread::spawn(move || {
-            let file = match File::open(&file_name) {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Failed to open file {}: {:?}", file_name, e);
-                    return;
-                }
+            let mut summary = StageSummary::new(Stage::Reader);
+            for (file_name, input) in inputs {
+                let result = match input {
+                    Input::File(file) if thread_count > 1 => {
+                        self.read_parallel(&file_name, file, thread_count, &mut summary)
+                    }
+                    Input::File(file) => self.read_sequential(&file_name, file, &mut summary),
+                    Input::Stdin => {
+                        self.read_sequential(&file_name, io::stdin().lock(), &mut summary)
+                    }
+                };
+                result.wrap_err_with(|| format!("Failed to read {}", file_name))?;
+            }
+            Ok(summary)
+        });
+
+        Ok(handle)
+    }
+
+    fn read_sequential(
+        &self,
+        file_name: &Arc<str>,
+        input: impl Read,
+        summary: &mut StageSummary,
+    ) -> Result<()> {
+        let mut rdr = csv_reader_builder().from_reader(TerminatorTracker::new(input));
+        let headers = canonical_headers(rdr.headers()?);
+
+        loop {
+            let mut record = StringRecord::new();
+            let result = match rdr.read_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => Ok(record),
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => Err(e),
             };
-            let mut rdr = Reader::from_reader(file);
-
-            for result in rdr.deserialize() {
-                match result {
-                    Ok(tx) => {
-                        let tx: AnyTransaction = tx;
-                        if self.tx.send(tx).is_err() {
-                            break; // Receiver has been dropped
-                        }
+
+            let tracker = rdr.get_mut();
+            let row = parse_record(&headers, result, |byte| tracker.skipped_lines(byte));
+            if !self.rows.send(file_name, row, 0, summary) {
+                break; // Receiver has been dropped
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_parallel(
+        &self,
+        file_name: &Arc<str>,
+        file: File,
+        thread_count: u8,
+        summary: &mut StageSummary,
+    ) -> Result<()> {
+        let file_len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+
+        let mut header_line = Vec::new();
+        let data_start = file.read_until(b'\n', &mut header_line)? as u64;
+        let Some(headers) = csv_reader_builder()
+            .has_headers(false)
+            .from_reader(header_line.as_slice())
+            .records()
+            .next()
+        else {
+            // An empty file has no rows, as when it is read sequentially.
+            return Ok(());
+        };
+        let headers = canonical_headers(&headers?);
+
+        let chunk_count = (file_len - data_start).div_ceil(CHUNK_SIZE);
+        let thread_count = thread_count as u64;
+
+        // Chunks are dealt round-robin, so reading worker outputs in the same order restores
+        // the file order. Each worker may only run one chunk ahead, bounding memory use.
+        let mut workers = Vec::new();
+        for worker in 0..thread_count {
+            let (tx_chunk, rx_chunk) = sync_channel::<Result<ParsedChunk>>(1);
+            let file_name = file_name.clone();
+            let headers = headers.clone();
+
+            let handle = thread::spawn(move || {
+                for chunk in (worker..chunk_count).step_by(thread_count as usize) {
+                    let start = data_start + chunk * CHUNK_SIZE;
+                    let end = (start + CHUNK_SIZE).min(file_len);
+                    let parsed = parse_chunk(&file_name, &headers, start, end, chunk == 0);
+                    if tx_chunk.send(parsed).is_err() {
+                        break; // Receiver has been dropped
                     }
-                    Err(e) => eprintln!("Failed to deserialize transaction: {:?}", e),
+                }
+                Ok(())
+            });
+            workers.push(Some((rx_chunk, handle)));
+        }
+
+        // The header is line 1.
+        let mut line_base = 1;
+        for chunk in 0..chunk_count {
+            let worker = (chunk % thread_count) as usize;
+            let Some((rx_chunk, _)) = &workers[worker] else {
+                unreachable!("workers are only taken once they have died");
+            };
+            let parsed_chunk = match rx_chunk.recv() {
+                Ok(parsed_chunk) => parsed_chunk?,
+                Err(_) => {
+                    // The worker hung up early, which only happens if it panicked.
+                    let (_, handle) = workers[worker].take().unwrap();
+                    join_thread("csv parser", handle)?;
+                    return Err(eyre!("Parser thread for chunk {} stopped early", chunk));
+                }
+            };
+
+            for row in parsed_chunk.rows {
+                if !self.rows.send(file_name, row, line_base, summary) {
+                    return Ok(()); // Receiver has been dropped
                 }
             }
+            line_base += parsed_chunk.lines;
+        }
 
-            drop(self.tx);
-        });
+        for (rx_chunk, handle) in workers.into_iter().flatten() {
+            drop(rx_chunk);
+            join_thread("csv parser", handle)?;
+        }
 
-        Ok(handle)
+        Ok(())
+    }
+}
+
+/// Partner files come padded and with missing trailing columns, both of which are accepted.
+fn csv_reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.trim(Trim::All).flexible(true);
+    builder
+}
+
+/// Map header aliases seen in partner files onto the names `AnyTransaction` expects, ignoring
+/// case, padding and a byte order mark.
+fn canonical_headers(headers: &StringRecord) -> StringRecord {
+    headers
+        .iter()
+        .map(|header| {
+            let header = header
+                .trim_start_matches('\u{feff}')
+                .trim()
+                .to_ascii_lowercase()
+                .replace([' ', '-'], "_");
+            match header.as_str() {
+                "type" | "tx_type" | "transaction_type" | "kind" => "type".to_string(),
+                "client" | "client_id" | "clientid" | "customer" | "customer_id" => {
+                    "client".to_string()
+                }
+                "tx" | "tx_id" | "txid" | "transaction" | "transaction_id" => "tx".to_string(),
+                "amount" | "value" => "amount".to_string(),
+                _ => header,
+            }
+        })
+        .collect()
+}
+
+/// Parse the lines starting within `[start, end)`. Unless this is the first chunk, the line
+/// straddling `start` belongs to the previous chunk and is skipped.
+fn parse_chunk(
+    file_name: &Arc<str>,
+    headers: &StringRecord,
+    start: u64,
+    end: u64,
+    is_first: bool,
+) -> Result<ParsedChunk> {
+    let mut file = BufReader::new(File::open(&**file_name)?);
+    let mut pos = if is_first { start } else { start - 1 };
+    file.seek(SeekFrom::Start(pos))?;
+    if !is_first {
+        pos += file.skip_until(b'\n')? as u64;
+    }
+
+    let mut bytes = Vec::with_capacity((end - start) as usize);
+    while pos < end {
+        let read = file.read_until(b'\n', &mut bytes)?;
+        if read == 0 {
+            break;
+        }
+        pos += read as u64;
+    }
+
+    let mut lines = bytes.iter().filter(|b| **b == b'\n').count() as u64;
+    if bytes.last().is_some_and(|b| *b != b'\n') {
+        lines += 1;
+    }
+
+    let rows = csv_reader_builder()
+        .has_headers(false)
+        .from_reader(bytes.as_slice())
+        .records()
+        .map(|result| {
+            parse_record(headers, result, |byte| {
+                skipped_lines(bytes[byte as usize..].iter().copied())
+            })
+        })
+        .collect();
+
+    Ok(ParsedChunk { rows, lines })
+}
+
+/// Deserialize a record, turning failures into rejections.
+///
+/// csv reports a record's position from before it skips the rest of a `\r\n` or any blank lines,
+/// so `skipped_lines` is given the reported byte offset to count the newlines in between.
+fn parse_record(
+    headers: &StringRecord,
+    result: csv::Result<StringRecord>,
+    skipped_lines: impl FnOnce(u64) -> u64,
+) -> ParsedRow {
+    let position = match &result {
+        Ok(record) => record.position(),
+        Err(e) => e.position(),
+    };
+    let line = position
+        .map(|p| p.line() + skipped_lines(p.byte()))
+        .unwrap_or_default();
+
+    let record = match result {
+        Ok(record) => record,
+        Err(e) => return ParsedRow::Rejected(line, RejectedRecord::default(), reject_detail(e)),
+    };
+
+    match record.deserialize::<AnyTransaction>(Some(headers)) {
+        Ok(tx) => ParsedRow::Transaction(line, tx),
+        Err(e) => ParsedRow::Rejected(line, raw_record(headers, &record), reject_detail(e)),
+    }
+}
+
+/// Newlines in the run of line terminators at the start of `bytes`.
+fn skipped_lines(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes
+        .take_while(|b| *b == b'\r' || *b == b'\n')
+        .filter(|b| *b == b'\n')
+        .count() as u64
+}
+
+/// Remembers where line terminators were in a stream so `skipped_lines` can be answered after
+/// csv has consumed the bytes. Only offsets csv may still ask about are kept.
+struct TerminatorTracker<R> {
+    inner: R,
+    offset: u64,
+    terminators: VecDeque<(u64, u8)>,
+}
+
+impl<R> TerminatorTracker<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            offset: 0,
+            terminators: VecDeque::new(),
+        }
+    }
+
+    fn skipped_lines(&mut self, from: u64) -> u64 {
+        while self
+            .terminators
+            .front()
+            .is_some_and(|(offset, _)| *offset < from)
+        {
+            self.terminators.pop_front();
+        }
+
+        let run = self
+            .terminators
+            .iter()
+            .zip(from..)
+            .take_while(|((offset, _), expected)| offset == expected)
+            .map(|((_, b), _)| *b);
+        skipped_lines(run)
+    }
+}
+
+impl<R: Read> Read for TerminatorTracker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for (i, b) in buf[..read].iter().enumerate() {
+            if *b == b'\r' || *b == b'\n' {
+                self.terminators.push_back((self.offset + i as u64, *b));
+            }
+        }
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Pick the transaction fields out of a row that failed to deserialize.
+fn raw_record(headers: &StringRecord, record: &StringRecord) -> RejectedRecord {
+    let field = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .and_then(|i| record.get(i))
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    RejectedRecord {
+        command_type: field("type"),
+        client: field("client"),
+        tx: field("tx"),
+        amount: field("amount"),
     }
 }
 
@@ -50,7 +365,7 @@ impl CsvReader {
 mod tests {
 
     use super::*;
-    use std::sync::mpsc::channel;
+    use std::sync::mpsc::sync_channel;
 
     use std::io::Write;
     use tempfile::NamedTempFile;
@@ -61,34 +376,251 @@ mod tests {
         writeln!(temp_file, "type,client,tx,amount").unwrap();
         writeln!(temp_file, "deposit,1,1,1.0").unwrap();
         writeln!(temp_file, "withdrawal,2,2,2.0").unwrap();
+        writeln!(temp_file, "deposit,one,4,2.0").unwrap();
         writeln!(temp_file, "deposit,1,3,2.0").unwrap();
 
-        let (tx, rx) = channel();
+        let (tx, rx) = sync_channel(16);
+        let (tx_rejection, rx_rejection) = sync_channel(16);
 
-        let csv_reader = CsvReader::new(tx);
+        let csv_reader = CsvReader::new(tx, tx_rejection);
         let handle = csv_reader
-            .start(temp_file.path().to_str().unwrap().to_string(), 1)
+            .start(vec![temp_file.path().to_str().unwrap().to_string()], 1)
             .unwrap();
 
-        handle.join().unwrap();
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!((summary.accepted, summary.rejected), (3, 1));
 
-        let transactions: Vec<AnyTransaction> = rx.iter().collect();
+        let sourced: Vec<Sourced<AnyTransaction>> = rx.iter().collect();
+        let lines: Vec<u64> = sourced.iter().map(|s| s.line).collect();
+        assert_eq!(lines, vec![2, 3, 5]);
 
+        let transactions: Vec<AnyTransaction> = sourced.into_iter().map(|s| s.value).collect();
         assert_eq!(transactions.len(), 3);
 
         assert_eq!(transactions[0].command_type, CommandType::Deposit);
         assert_eq!(transactions[0].client_id, 1);
         assert_eq!(transactions[0].tx_id, 1);
-        assert_eq!(transactions[0].amount, Some(1.0));
+        assert_eq!(transactions[0].amount, Some("1.0".parse().unwrap()));
 
         assert_eq!(transactions[1].command_type, CommandType::Withdrawal);
         assert_eq!(transactions[1].client_id, 2);
         assert_eq!(transactions[1].tx_id, 2);
-        assert_eq!(transactions[1].amount, Some(2.0));
+        assert_eq!(transactions[1].amount, Some("2.0".parse().unwrap()));
 
         assert_eq!(transactions[2].command_type, CommandType::Deposit);
         assert_eq!(transactions[2].client_id, 1);
         assert_eq!(transactions[2].tx_id, 3);
-        assert_eq!(transactions[2].amount, Some(2.0));
+        assert_eq!(transactions[2].amount, Some("2.0".parse().unwrap()));
+
+        let rejections: Vec<Rejection> = rx_rejection.iter().collect();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].line, 4);
+        assert_eq!(rejections[0].stage, Stage::Reader);
+        assert_eq!(rejections[0].reason, RejectionReason::MalformedRow);
+        assert_eq!(
+            rejections[0].record,
+            RejectedRecord {
+                command_type: "deposit".to_string(),
+                client: "one".to_string(),
+                tx: "4".to_string(),
+                amount: "2.0".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_csv_reader_applies_backpressure() {
+        let rows = 200_000;
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "type,client,tx,amount").unwrap();
+        for tx_id in 0..rows {
+            writeln!(temp_file, "deposit,1,{},1.0", tx_id).unwrap();
+        }
+
+        let capacity = 8;
+        let (tx, rx) = sync_channel(capacity);
+        let (tx_rejection, _rx_rejection) = sync_channel(capacity);
+
+        let handle = CsvReader::new(tx, tx_rejection)
+            .start(vec![temp_file.path().to_str().unwrap().to_string()], 1)
+            .unwrap();
+
+        // With nobody consuming, the reader parks once the channel is full rather than
+        // buffering the whole file in memory.
+        thread::sleep(std::time::Duration::from_millis(200));
+        assert!(!handle.is_finished());
+
+        assert_eq!(rx.iter().count(), rows);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_csv_reader_parallel_matches_sequential() {
+        // Several chunks worth of rows, with malformed ones scattered across chunk boundaries
+        // and no newline after the last row.
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(temp_file, "type,client,tx,amount").unwrap();
+        for tx_id in 0..150_000 {
+            if tx_id % 997 == 0 {
+                write!(temp_file, "\ndeposit,one,{},1.0", tx_id).unwrap();
+            } else {
+                write!(temp_file, "\ndeposit,{},{},{}.5", tx_id % 7, tx_id, tx_id).unwrap();
+            }
+        }
+        assert!(temp_file.as_file().metadata().unwrap().len() > 3 * CHUNK_SIZE);
+
+        let read = |file: &NamedTempFile, thread_count| {
+            let (tx, rx) = sync_channel(1024);
+            let (tx_rejection, rx_rejection) = sync_channel(1024);
+            let handle = CsvReader::new(tx, tx_rejection)
+                .start(
+                    vec![file.path().to_str().unwrap().to_string()],
+                    thread_count,
+                )
+                .unwrap();
+
+            let rejections = thread::spawn(move || {
+                rx_rejection
+                    .iter()
+                    .map(|r: Rejection| (r.line, r.record))
+                    .collect::<Vec<_>>()
+            });
+            let transactions = rx
+                .iter()
+                .map(|s: Sourced<AnyTransaction>| (s.line, s.value.tx_id, s.value.amount))
+                .collect::<Vec<_>>();
+            handle.join().unwrap().unwrap();
+            (transactions, rejections.join().unwrap())
+        };
+
+        let (transactions, rejections) = read(&temp_file, 1);
+        assert_eq!(transactions.len() + rejections.len(), 150_000);
+        assert_eq!(transactions.last().unwrap().0, 150_001);
+        assert_eq!(rejections[1].0, 999);
+
+        let empty_file = NamedTempFile::new().unwrap();
+        for thread_count in [2, 4] {
+            assert_eq!(
+                read(&temp_file, thread_count),
+                (transactions.clone(), rejections.clone())
+            );
+            assert_eq!(read(&empty_file, thread_count), (Vec::new(), Vec::new()));
+        }
+    }
+
+    #[test]
+    fn test_csv_reader_fails_on_missing_file() {
+        let (tx, _rx) = sync_channel(16);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
+
+        let error = CsvReader::new(tx, tx_rejection)
+            .start(vec!["does-not-exist.csv".to_string()], 1)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to open input file does-not-exist.csv"
+        );
+    }
+
+    #[test]
+    fn test_csv_reader_reads_files_in_order() {
+        let mut day_1 = NamedTempFile::new().unwrap();
+        writeln!(day_1, "type,client,tx,amount").unwrap();
+        writeln!(day_1, "deposit,1,1,1.0").unwrap();
+        writeln!(day_1, "deposit,1,2,x").unwrap();
+        // Later files may order their columns differently.
+        let mut day_2 = NamedTempFile::new().unwrap();
+        writeln!(day_2, "client,type,tx,amount").unwrap();
+        writeln!(day_2, "2,deposit,3,1.0").unwrap();
+
+        let file_names: Vec<String> = [&day_1, &day_2]
+            .iter()
+            .map(|f| f.path().to_str().unwrap().to_string())
+            .collect();
+
+        for thread_count in [1, 2] {
+            let (tx, rx) = sync_channel(16);
+            let (tx_rejection, rx_rejection) = sync_channel(16);
+            let handle = CsvReader::new(tx, tx_rejection)
+                .start(file_names.clone(), thread_count)
+                .unwrap();
+            handle.join().unwrap().unwrap();
+
+            let sourced: Vec<(String, u64, u32)> = rx
+                .iter()
+                .map(|s| (s.file.to_string(), s.line, s.value.tx_id))
+                .collect();
+            assert_eq!(
+                sourced,
+                vec![(file_names[0].clone(), 2, 1), (file_names[1].clone(), 2, 3)]
+            );
+
+            let rejections: Vec<Rejection> = rx_rejection.iter().collect();
+            assert_eq!(rejections.len(), 1);
+            assert_eq!(&*rejections[0].file, file_names[0]);
+            assert_eq!(rejections[0].line, 3);
+        }
+    }
+
+    #[test]
+    fn test_csv_reader_tolerates_messy_rows() {
+        let corpus = "\u{feff}Type, Client_ID , TX ,Amount\n\
+deposit, 1, 1, 1.0\n\
+Deposit,1,2,2.5\n\
+  WITHDRAWAL ,1,3, 0.5  \n\
+dispute,1,1\n\
+Resolve,1,1,\n\
+deposit,2,4,3.0\r\n\
+\n\
+\"deposit\",\"2\",\" 5 \",\"1.0\"\n\
+deposit,2,6,abc\n\
+transfer,2,7,1.0\n\
+deposit,2,8\n\
+deposit,2,9,1.0,\n\
+chargeback,2,4";
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(corpus.as_bytes()).unwrap();
+
+        let amount = |s: &str| Some(s.parse().unwrap());
+        let expected = vec![
+            (2, CommandType::Deposit, 1, 1, amount("1.0")),
+            (3, CommandType::Deposit, 1, 2, amount("2.5")),
+            (4, CommandType::Withdrawal, 1, 3, amount("0.5")),
+            (5, CommandType::Dispute, 1, 1, None),
+            (6, CommandType::Resolve, 1, 1, None),
+            (7, CommandType::Deposit, 2, 4, amount("3.0")),
+            (9, CommandType::Deposit, 2, 5, amount("1.0")),
+            // Unknown types and missing amounts are left for the command converter to reject.
+            (11, CommandType::Unknown, 2, 7, amount("1.0")),
+            (12, CommandType::Deposit, 2, 8, None),
+            (13, CommandType::Deposit, 2, 9, amount("1.0")),
+            (14, CommandType::Chargeback, 2, 4, None),
+        ];
+
+        for thread_count in [1, 2] {
+            let (tx, rx) = sync_channel(64);
+            let (tx_rejection, rx_rejection) = sync_channel(64);
+            let handle = CsvReader::new(tx, tx_rejection)
+                .start(
+                    vec![temp_file.path().to_str().unwrap().to_string()],
+                    thread_count,
+                )
+                .unwrap();
+            handle.join().unwrap().unwrap();
+
+            let transactions: Vec<_> = rx
+                .iter()
+                .map(|s| {
+                    let tx = s.value;
+                    (s.line, tx.command_type, tx.client_id, tx.tx_id, tx.amount)
+                })
+                .collect();
+            assert_eq!(transactions, expected);
+
+            let rejections: Vec<Rejection> = rx_rejection.iter().collect();
+            assert_eq!(rejections.len(), 1);
+            assert_eq!(rejections[0].line, 10);
+            assert_eq!(rejections[0].record.amount, "abc");
+        }
     }
 }
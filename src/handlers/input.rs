@@ -0,0 +1,159 @@
+use crate::error::*;
+use crate::transaction::*;
+
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    fmt,
+    fs::File,
+    sync::{mpsc::SyncSender, Arc},
+};
+
+/// Input name meaning stdin rather than a file.
+pub const STDIN: &str = "-";
+
+/// How transactions are encoded in an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    /// A header row followed by one transaction per row.
+    #[default]
+    Csv,
+    /// One json object per line.
+    Jsonl,
+}
+
+impl InputFormat {
+    /// `.jsonl` and `.ndjson` files are json lines, anything else is csv.
+    pub fn from_file_name(file_name: &str) -> Self {
+        match file_name.rsplit_once('.') {
+            Some((_, "jsonl" | "ndjson")) => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+
+    /// The format of a batch of inputs, from their extensions. Stdin takes the format of the
+    /// files it is read with. Fails if the files disagree.
+    pub fn detect(file_names: &[String]) -> Result<Self> {
+        let mut formats = file_names
+            .iter()
+            .filter(|file_name| *file_name != STDIN)
+            .map(|file_name| (file_name, InputFormat::from_file_name(file_name)));
+        let Some((first_name, format)) = formats.next() else {
+            return Ok(InputFormat::default());
+        };
+        match formats.find(|(_, other)| *other != format) {
+            Some((other_name, _)) => Err(eyre!(
+                "Inputs {} and {} are in different formats, pick one with --input-format",
+                first_name,
+                other_name
+            )),
+            None => Ok(format),
+        }
+    }
+}
+
+pub(super) enum Input {
+    Stdin,
+    File(File),
+}
+
+/// Inputs are all opened up front so a missing one fails before the reader thread starts.
+pub(super) fn open_inputs(file_names: Vec<String>) -> Result<Vec<(Arc<str>, Input)>> {
+    file_names
+        .into_iter()
+        .map(|file_name| {
+            let input = if file_name == STDIN {
+                Input::Stdin
+            } else {
+                Input::File(
+                    File::open(&file_name)
+                        .wrap_err_with(|| format!("Failed to open input file {}", file_name))?,
+                )
+            };
+            Ok((Arc::from(file_name), input))
+        })
+        .collect()
+}
+
+/// A row parsed by a reader, lines may be relative to the start of a chunk.
+pub(super) enum ParsedRow {
+    Transaction(u64, AnyTransaction),
+    /// Line, the raw fields and what was wrong with them.
+    Rejected(u64, RejectedRecord, String),
+}
+
+pub(super) fn reject_detail(e: impl fmt::Display) -> String {
+    format!("Failed to deserialize transaction: {}", e)
+}
+
+/// Passes parsed rows on, whatever format they were read from.
+pub(super) struct RowSender {
+    tx: SyncSender<Sourced<AnyTransaction>>,
+    rejections: SyncSender<Rejection>,
+}
+
+impl RowSender {
+    pub(super) fn new(
+        tx: SyncSender<Sourced<AnyTransaction>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Self {
+        Self { tx, rejections }
+    }
+
+    /// Returns false once the downstream stage has gone away.
+    pub(super) fn send(
+        &self,
+        file_name: &Arc<str>,
+        row: ParsedRow,
+        line_base: u64,
+        summary: &mut StageSummary,
+    ) -> bool {
+        match row {
+            ParsedRow::Transaction(line, tx) => {
+                summary.accepted += 1;
+                let sourced = Sourced::new(file_name.clone(), line_base + line, tx);
+                self.tx.send(sourced).is_ok()
+            }
+            ParsedRow::Rejected(line, record, detail) => {
+                summary.rejected += 1;
+                let _ = self.rejections.send(Rejection {
+                    file: file_name.clone(),
+                    line: line_base + line,
+                    stage: Stage::Reader,
+                    reason: RejectionReason::MalformedRow,
+                    record,
+                    detail,
+                });
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_input_format() -> Result<()> {
+        let names = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            InputFormat::detect(&names(&["a.csv", "b.txt"]))?,
+            InputFormat::Csv
+        );
+        assert_eq!(
+            InputFormat::detect(&names(&["a.jsonl", "-", "b.ndjson"]))?,
+            InputFormat::Jsonl
+        );
+        assert_eq!(InputFormat::detect(&names(&["-"]))?, InputFormat::Csv);
+        assert_eq!(
+            InputFormat::detect(&names(&["a.csv", "b.jsonl"]))
+                .unwrap_err()
+                .to_string(),
+            "Inputs a.csv and b.jsonl are in different formats, pick one with --input-format"
+        );
+
+        Ok(())
+    }
+}
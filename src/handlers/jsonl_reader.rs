@@ -0,0 +1,222 @@
+use super::input::*;
+use crate::error::*;
+use crate::transaction::*;
+use crate::types::*;
+
+use eyre::*;
+use serde::Deserialize;
+use serde_json::{value::RawValue, Map, Value};
+use std::result::Result::Ok;
+use std::{
+    io::{self, BufRead, BufReader},
+    sync::{mpsc::SyncSender, Arc},
+    thread::{self, JoinHandle},
+};
+
+/// Reads newline-delimited json objects with the same fields as the csv columns, e.g.
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`.
+pub struct JsonlReader {
+    rows: RowSender,
+}
+
+/// The json form of an `AnyTransaction`. The amount is kept as written so a number is parsed
+/// as exactly as a string.
+#[derive(Deserialize)]
+struct JsonTransaction<'a> {
+    #[serde(rename = "type")]
+    command_type: CommandType,
+    #[serde(rename = "tx")]
+    tx_id: TxId,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(borrow, default)]
+    amount: Option<&'a RawValue>,
+}
+
+impl JsonlReader {
+    pub fn new(tx: SyncSender<Sourced<AnyTransaction>>, rejections: SyncSender<Rejection>) -> Self {
+        Self {
+            rows: RowSender::new(tx, rejections),
+        }
+    }
+
+    /// Report and ignore erroneous lines, skipping blank ones.
+    ///
+    /// Inputs are read one after the other as a single stream.
+    pub fn start(self, file_names: Vec<String>) -> Result<JoinHandle<Result<StageSummary>>> {
+        let inputs = open_inputs(file_names)?;
+
+        let handle = thread::spawn(move || {
+            let mut summary = StageSummary::new(Stage::Reader);
+            for (file_name, input) in inputs {
+                let result = match input {
+                    Input::File(file) => self.read(&file_name, BufReader::new(file), &mut summary),
+                    Input::Stdin => self.read(&file_name, io::stdin().lock(), &mut summary),
+                };
+                result.wrap_err_with(|| format!("Failed to read {}", file_name))?;
+            }
+            Ok(summary)
+        });
+
+        Ok(handle)
+    }
+
+    fn read(
+        &self,
+        file_name: &Arc<str>,
+        mut input: impl BufRead,
+        summary: &mut StageSummary,
+    ) -> Result<()> {
+        let mut bytes = Vec::new();
+        let mut line = 0;
+        loop {
+            bytes.clear();
+            if input.read_until(b'\n', &mut bytes)? == 0 {
+                break;
+            }
+            line += 1;
+            if bytes.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            if !self
+                .rows
+                .send(file_name, parse_line(line, &bytes), 0, summary)
+            {
+                break; // Receiver has been dropped
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Deserialize a line, turning failures into rejections.
+fn parse_line(line: u64, bytes: &[u8]) -> ParsedRow {
+    let parsed = serde_json::from_slice::<JsonTransaction>(bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            let amount = json.amount.map(parse_amount).transpose()?;
+            Ok(AnyTransaction {
+                command_type: json.command_type,
+                tx_id: json.tx_id,
+                client_id: json.client_id,
+                amount,
+            })
+        });
+
+    match parsed {
+        Ok(tx) => ParsedRow::Transaction(line, tx),
+        Err(e) => ParsedRow::Rejected(line, raw_record(bytes), reject_detail(e)),
+    }
+}
+
+/// Amounts may be decimal strings or numbers, neither goes through a float.
+fn parse_amount(raw: &RawValue) -> std::result::Result<Money, String> {
+    let text = match raw.get() {
+        text if text.starts_with('"') => {
+            serde_json::from_str::<String>(text).map_err(|e| e.to_string())?
+        }
+        text => text.to_string(),
+    };
+    text.parse::<Money>()
+        .map_err(|e| format!("amount {}: {}", raw.get(), e))
+}
+
+/// Pick the transaction fields out of a line that failed to deserialize.
+fn raw_record(bytes: &[u8]) -> RejectedRecord {
+    let Ok(object) = serde_json::from_slice::<Map<String, Value>>(bytes) else {
+        return RejectedRecord::default();
+    };
+    let field = |name: &str| match object.get(name) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    };
+
+    RejectedRecord {
+        command_type: field("type"),
+        client: field("client"),
+        tx: field("tx"),
+        amount: field("amount"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc::sync_channel;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_jsonl_reader() {
+        let corpus = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+{"type":"Withdrawal","client":1,"tx":2,"amount":0.25}
+
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "resolve", "client": 1, "tx": 1, "amount": null}
+{"type": "deposit", "client": "one", "tx": 3, "amount": "2.0"}
+{"type": "deposit", "client": 2, "tx": 4, "amount": "1.23456"}
+not json
+{"type": "transfer", "client": 2, "tx": 5, "amount": "1.0"}
+{"type": "deposit", "client": 2, "tx": 6, "amount": 922337203685477.5807}"#;
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(corpus.as_bytes()).unwrap();
+
+        let (tx, rx) = sync_channel(16);
+        let (tx_rejection, rx_rejection) = sync_channel(16);
+        let handle = JsonlReader::new(tx, tx_rejection)
+            .start(vec![temp_file.path().to_str().unwrap().to_string()])
+            .unwrap();
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!((summary.accepted, summary.rejected), (6, 3));
+
+        let amount = |s: &str| Some(s.parse().unwrap());
+        let transactions: Vec<_> = rx
+            .iter()
+            .map(|s| {
+                let tx = s.value;
+                (s.line, tx.command_type, tx.client_id, tx.tx_id, tx.amount)
+            })
+            .collect();
+        assert_eq!(
+            transactions,
+            vec![
+                (1, CommandType::Deposit, 1, 1, amount("1.5")),
+                (2, CommandType::Withdrawal, 1, 2, amount("0.25")),
+                (4, CommandType::Dispute, 1, 1, None),
+                (5, CommandType::Resolve, 1, 1, None),
+                (9, CommandType::Unknown, 2, 5, amount("1.0")),
+                (
+                    10,
+                    CommandType::Deposit,
+                    2,
+                    6,
+                    amount("922337203685477.5807")
+                ),
+            ]
+        );
+
+        let rejections: Vec<Rejection> = rx_rejection.iter().collect();
+        let lines: Vec<u64> = rejections.iter().map(|r| r.line).collect();
+        assert_eq!(lines, vec![6, 7, 8]);
+        assert!(rejections
+            .iter()
+            .all(|r| r.stage == Stage::Reader && r.reason == RejectionReason::MalformedRow));
+        assert_eq!(
+            rejections[0].record,
+            RejectedRecord {
+                command_type: "deposit".to_string(),
+                client: "one".to_string(),
+                tx: "3".to_string(),
+                amount: "2.0".to_string(),
+            }
+        );
+        assert_eq!(
+            rejections[1].detail,
+            "Failed to deserialize transaction: amount \"1.23456\": amount has more than 4 decimal places"
+        );
+        assert_eq!(rejections[2].record, RejectedRecord::default());
+    }
+}
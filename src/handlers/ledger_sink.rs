@@ -0,0 +1,100 @@
+use crate::ledger::*;
+
+use csv::Writer;
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    fs::OpenOptions,
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+};
+
+/// Collects ledger entries from every account manager shard and writes them as a csv, numbered
+/// in the order they arrive. Shards interleave, but each client's entries stay in order.
+pub struct LedgerSink {
+    rx: Receiver<LedgerEntry>,
+}
+
+impl LedgerSink {
+    pub fn new(rx: Receiver<LedgerEntry>) -> Self {
+        Self { rx }
+    }
+
+    /// The thread returns the number of entries written.
+    pub fn start(self, output_filename: String) -> Result<JoinHandle<Result<u64>>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&output_filename)
+            .wrap_err_with(|| format!("Failed to open ledger file {}", output_filename))?;
+        let mut wtr = Writer::from_writer(file);
+
+        let handle = thread::spawn(move || {
+            let mut sequence = 0;
+            while let Ok(mut entry) = self.rx.recv() {
+                sequence += 1;
+                entry.sequence = sequence;
+                wtr.serialize(&entry)
+                    .wrap_err("Failed to write ledger file")?;
+            }
+            wtr.flush().wrap_err("Failed to flush ledger file")?;
+            Ok(sequence)
+        });
+
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_ledger_sink() -> Result<()> {
+        let temp_output = NamedTempFile::new()?;
+        let (tx, rx) = sync_channel(16);
+
+        let handle = LedgerSink::new(rx).start(temp_output.path().to_str().unwrap().to_string())?;
+
+        let entry = LedgerEntry {
+            sequence: 0,
+            tx_id: 1,
+            client_id: 2,
+            kind: LedgerEntryKind::Deposit,
+            delta_available: "1.5".parse()?,
+            delta_held: "0".parse()?,
+            available: "1.5".parse()?,
+            held: "0".parse()?,
+            total: "1.5".parse()?,
+            locked: false,
+        };
+        tx.send(entry.clone())?;
+        tx.send(entry.clone())?;
+        drop(tx);
+
+        assert_eq!(handle.join().unwrap()?, 2);
+
+        let expected_output = "\
+sequence,tx,client,kind,delta_available,delta_held,available,held,total,locked\n\
+1,1,2,deposit,1.5000,0.0000,1.5000,0.0000,1.5000,false\n\
+2,1,2,deposit,1.5000,0.0000,1.5000,0.0000,1.5000,false\n";
+        assert_eq!(
+            std::fs::read_to_string(temp_output.path())?,
+            expected_output
+        );
+
+        let entries = read_ledger(temp_output.path())?;
+        assert_eq!(
+            entries[1],
+            LedgerEntry {
+                sequence: 2,
+                ..entry
+            }
+        );
+
+        Ok(())
+    }
+}
@@ -1,7 +1,60 @@
 mod account_manager;
 mod command_converter;
 mod csv_reader;
+mod input;
+mod jsonl_reader;
+mod ledger_sink;
+mod rejection_sink;
+mod router;
 
 pub use account_manager::*;
 pub use command_converter::*;
 pub use csv_reader::*;
+pub use input::{InputFormat, STDIN};
+pub use jsonl_reader::*;
+pub use ledger_sink::*;
+pub use rejection_sink::*;
+pub use router::*;
+
+use eyre::*;
+use std::result::Result::Ok;
+use std::thread::JoinHandle;
+
+/// Wait for a pipeline thread, turning a panic into an error naming the thread.
+pub fn join_thread<T>(name: &str, handle: JoinHandle<Result<T>>) -> Result<T> {
+    match handle.join() {
+        Ok(result) => result.wrap_err_with(|| format!("The {} thread failed", name)),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            Err(eyre!("The {} thread panicked: {}", name, message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_join_thread() {
+        let handle = thread::spawn(|| Ok(1));
+        assert_eq!(join_thread("router", handle).unwrap(), 1);
+
+        let handle = thread::spawn(|| Err::<(), _>(eyre!("Disk full")));
+        let error = join_thread("reader", handle).unwrap_err();
+        assert_eq!(error.to_string(), "The reader thread failed");
+        assert_eq!(error.root_cause().to_string(), "Disk full");
+
+        let handle = thread::spawn(|| -> Result<()> { panic!("Shard {} fell over", 3) });
+        let error = join_thread("account_manager", handle).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The account_manager thread panicked: Shard 3 fell over"
+        );
+    }
+}
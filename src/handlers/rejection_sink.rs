@@ -0,0 +1,123 @@
+use crate::error::*;
+
+use csv::Writer;
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    fs::OpenOptions,
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+};
+
+/// Collects rejections from every stage of the pipeline.
+/// Writes them as a csv report when given a file, otherwise logs them to stderr.
+pub struct RejectionSink {
+    rx: Receiver<Rejection>,
+}
+
+impl RejectionSink {
+    pub fn new(rx: Receiver<Rejection>) -> Self {
+        Self { rx }
+    }
+
+    /// The thread returns the number of rejections recorded.
+    pub fn start(self, output_filename: Option<String>) -> Result<JoinHandle<Result<u64>>> {
+        let mut wtr = match output_filename {
+            Some(ref s) => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(s)
+                    .wrap_err_with(|| format!("Failed to open rejections file {}", s))?;
+                let mut wtr = Writer::from_writer(file);
+                wtr.write_record([
+                    "file", "line", "stage", "reason", "type", "client", "tx", "amount", "detail",
+                ])?;
+                Some(wtr)
+            }
+            None => None,
+        };
+
+        let handle = thread::spawn(move || {
+            let mut count = 0;
+            while let Ok(rejection) = self.rx.recv() {
+                count += 1;
+                let Some(wtr) = wtr.as_mut() else {
+                    eprintln!(
+                        "Rejected {}:{} at {}: {} {:?}",
+                        rejection.file,
+                        rejection.line,
+                        rejection.stage.as_str(),
+                        rejection.detail,
+                        rejection.record
+                    );
+                    continue;
+                };
+
+                wtr.write_record([
+                    &rejection.file,
+                    rejection.line.to_string().as_str(),
+                    rejection.stage.as_str(),
+                    rejection.reason.code(),
+                    &rejection.record.command_type,
+                    &rejection.record.client,
+                    &rejection.record.tx,
+                    &rejection.record.amount,
+                    &rejection.detail,
+                ])
+                .wrap_err("Failed to write rejections file")?;
+            }
+
+            if let Some(mut wtr) = wtr {
+                wtr.flush().wrap_err("Failed to flush rejections file")?;
+            }
+
+            Ok(count)
+        });
+
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use std::sync::mpsc::sync_channel;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_rejection_sink() {
+        let temp_output = NamedTempFile::new().unwrap();
+        let (tx, rx) = sync_channel(16);
+
+        let handle = RejectionSink::new(rx)
+            .start(Some(temp_output.path().to_str().unwrap().to_string()))
+            .unwrap();
+
+        tx.send(Rejection {
+            file: "day-1.csv".into(),
+            line: 3,
+            stage: Stage::AccountManager,
+            reason: RejectionReason::InsufficientFunds,
+            record: RejectedRecord {
+                command_type: "withdrawal".to_string(),
+                client: "1".to_string(),
+                tx: "2".to_string(),
+                amount: "5.0000".to_string(),
+            },
+            detail: RejectionReason::InsufficientFunds.to_string(),
+        })
+        .unwrap();
+        drop(tx);
+
+        assert_eq!(handle.join().unwrap().unwrap(), 1);
+
+        let expected_output = "\
+file,line,stage,reason,type,client,tx,amount,detail\n\
+day-1.csv,3,account_manager,insufficient_funds,withdrawal,1,2,5.0000,not enough available funds\n";
+
+        assert_eq!(read_to_string(temp_output.path()).unwrap(), expected_output);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::error::*;
+use crate::snapshot::*;
+use crate::transaction::*;
+use crate::tx_id_set::*;
+use crate::types::*;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    thread::JoinHandle,
+};
+
+/// Fans commands out to account manager shards, each owning a disjoint set of clients.
+///
+/// Deposits and withdrawals go to the shard of their client. Disputes, resolves and chargebacks
+/// go to the shard of the client that owns the referenced tx, so the shard holding that tx always
+/// answers the lookup. A single router thread sending over FIFO channels keeps every client's
+/// commands in input order.
+///
+/// Tx ids are unique across all clients, so duplicates are rejected here where every id is seen.
+pub struct Router {
+    rx: Receiver<Sourced<TransactionCommand>>,
+    shards: Vec<SyncSender<Sourced<TransactionCommand>>>,
+    rejections: SyncSender<Rejection>,
+    /// Every tx id claimed, including ids restored from a snapshot without a recorded
+    /// transaction, which no shard knows about.
+    claimed_tx_ids: TxIdSet,
+    /// Per shard, the tx ids of the transactions it holds, which disputes are routed by.
+    shard_tx_ids: Vec<TxIdSet>,
+    /// Index of each input file, to order rows across files.
+    input_index: HashMap<Arc<str>, usize>,
+    /// Per shard, the input index and line of the last command its write-ahead log recorded.
+    resume_after: Vec<Option<(usize, u64)>>,
+}
+
+impl Router {
+    pub fn new(
+        rx: Receiver<Sourced<TransactionCommand>>,
+        shards: Vec<SyncSender<Sourced<TransactionCommand>>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Self {
+        assert!(!shards.is_empty(), "Router needs at least one shard");
+        Self {
+            rx,
+            shard_tx_ids: shards.iter().map(|_| TxIdSet::new()).collect(),
+            shards,
+            rejections,
+            claimed_tx_ids: TxIdSet::new(),
+            input_index: HashMap::new(),
+            resume_after: Vec::new(),
+        }
+    }
+
+    /// Carry on from a snapshot, so its tx ids count as claimed and its transactions are routed
+    /// to the shards they were restored into.
+    pub fn with_snapshot(mut self, snapshot: &Snapshot) -> Self {
+        for (tx_id, transaction) in &snapshot.transactions {
+            self.claim(transaction.client_id, *tx_id);
+        }
+        for tx_id in &snapshot.claimed_tx_ids {
+            self.claimed_tx_ids.insert(*tx_id);
+        }
+        self
+    }
+
+    /// Carry on after a crash, dropping the commands each shard had already applied before it.
+    /// Those were replayed from the shards' write-ahead logs, whose last positions are passed as
+    /// (file, line). Inputs must be distinct so a file name identifies a single input.
+    pub fn with_resume(
+        mut self,
+        input_filenames: &[String],
+        positions: Vec<Option<(String, u64)>>,
+    ) -> eyre::Result<Self> {
+        self.input_index = input_filenames
+            .iter()
+            .enumerate()
+            .map(|(index, file)| (file.as_str().into(), index))
+            .collect();
+        self.resume_after = positions
+            .into_iter()
+            .map(|position| {
+                position
+                    .map(|(file, line)| match self.input_index.get(file.as_str()) {
+                        Some(index) => Ok((*index, line)),
+                        None => Err(eyre::eyre!(
+                            "The wal has entries from {}, which is not one of the inputs",
+                            file
+                        )),
+                    })
+                    .transpose()
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(self)
+    }
+
+    pub fn start(mut self) -> JoinHandle<eyre::Result<StageSummary>> {
+        thread::spawn(move || {
+            let mut summary = StageSummary::new(Stage::Router);
+            while let Ok(sourced) = self.rx.recv() {
+                let shard = self.target_shard(&sourced.value);
+                if self.already_applied(shard, &sourced) {
+                    continue;
+                }
+                if let Err(e) = self.claim_tx_id(&sourced.value) {
+                    summary.rejected += 1;
+                    let _ = self.rejections.send(Rejection::from_engine_error(
+                        Stage::Router,
+                        &sourced,
+                        &e,
+                    ));
+                    continue;
+                }
+
+                summary.accepted += 1;
+                if self.shards[shard].send(sourced).is_err() {
+                    break; // Receiver has been dropped
+                }
+            }
+            Ok(summary)
+        })
+    }
+
+    /// The shard that should handle the command.
+    fn target_shard(&self, tx_command: &TransactionCommand) -> usize {
+        match tx_command {
+            TransactionCommand::Deposit(Deposit { client_id, .. })
+            | TransactionCommand::Withdrawal(Withdrawal { client_id, .. }) => {
+                shard_for(*client_id, self.shards.len())
+            }
+            TransactionCommand::Dispute(Dispute { client_id, tx_id })
+            | TransactionCommand::Resolve(Resolve { client_id, tx_id })
+            | TransactionCommand::Chargeback(Chargeback { client_id, tx_id }) => {
+                // Unknown txs are left for the raising client's shard to reject.
+                self.shard_tx_ids
+                    .iter()
+                    .position(|tx_ids| tx_ids.contains(*tx_id))
+                    .unwrap_or_else(|| shard_for(*client_id, self.shards.len()))
+            }
+        }
+    }
+
+    /// Shards see commands in input order, so everything up to a shard's resume position was
+    /// handled before the crash, including this router's rejections.
+    fn already_applied(&self, shard: usize, sourced: &Sourced<TransactionCommand>) -> bool {
+        let Some(Some(resume_after)) = self.resume_after.get(shard) else {
+            return false;
+        };
+        match self.input_index.get(&sourced.file) {
+            Some(index) => (*index, sourced.line) <= *resume_after,
+            None => false,
+        }
+    }
+
+    /// Deposits and withdrawals claim their tx id, rejected if it was claimed before.
+    fn claim_tx_id(&mut self, tx_command: &TransactionCommand) -> Result<(), EngineError> {
+        let (client_id, tx_id) = match tx_command {
+            TransactionCommand::Deposit(deposit) => (deposit.client_id, deposit.tx_id),
+            TransactionCommand::Withdrawal(withdrawal) => (withdrawal.client_id, withdrawal.tx_id),
+            _ => return Ok(()),
+        };
+
+        if self.claimed_tx_ids.contains(tx_id) {
+            return Err(EngineError::new(
+                RejectionReason::DuplicateTransaction,
+                tx_command,
+            ));
+        }
+        self.claim(client_id, tx_id);
+
+        Ok(())
+    }
+
+    fn claim(&mut self, client_id: ClientId, tx_id: TxId) {
+        self.claimed_tx_ids.insert(tx_id);
+        self.shard_tx_ids[shard_for(client_id, self.shards.len())].insert(tx_id);
+    }
+}
+
+/// Sequential client ids spread evenly, which is all the hashing needed here.
+pub fn shard_for(client_id: ClientId, shard_count: usize) -> usize {
+    client_id as usize % shard_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn test_router() {
+        let (tx_tx_command, rx_tx_command) = sync_channel(16);
+        let (tx_rejection, rx_rejection) = sync_channel(16);
+        let (shard_txs, shard_rxs): (Vec<_>, Vec<_>) = (0..2).map(|_| sync_channel(16)).unzip();
+
+        let handle = Router::new(rx_tx_command, shard_txs, tx_rejection).start();
+
+        let commands = [
+            TransactionCommand::Deposit(Deposit {
+                client_id: 1,
+                tx_id: 1,
+                amount: "1.0".parse().unwrap(),
+            }),
+            TransactionCommand::Deposit(Deposit {
+                client_id: 2,
+                tx_id: 2,
+                amount: "1.0".parse().unwrap(),
+            }),
+            // Raised by client 2 against client 1's tx, so it goes to client 1's shard.
+            TransactionCommand::Dispute(Dispute {
+                client_id: 2,
+                tx_id: 1,
+            }),
+            TransactionCommand::Withdrawal(Withdrawal {
+                client_id: 2,
+                tx_id: 1,
+                amount: "1.0".parse().unwrap(),
+            }),
+        ];
+        for (line, tx_command) in commands.iter().enumerate() {
+            tx_tx_command
+                .send(Sourced::new(
+                    "test.csv".into(),
+                    line as u64,
+                    tx_command.clone(),
+                ))
+                .unwrap();
+        }
+        drop(tx_tx_command);
+
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!((summary.accepted, summary.rejected), (3, 1));
+
+        let lines = |rx: &Receiver<Sourced<TransactionCommand>>| {
+            rx.try_iter().map(|s| s.line).collect::<Vec<_>>()
+        };
+        assert_eq!(lines(&shard_rxs[0]), vec![1]);
+        assert_eq!(lines(&shard_rxs[1]), vec![0, 2]);
+
+        let rejections: Vec<Rejection> = rx_rejection.iter().collect();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].line, 3);
+        assert_eq!(rejections[0].stage, Stage::Router);
+        assert_eq!(rejections[0].reason, RejectionReason::DuplicateTransaction);
+    }
+}
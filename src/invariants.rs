@@ -0,0 +1,329 @@
+use crate::account::*;
+use crate::dispute::*;
+use crate::transaction::{TransactionKind, TransactionState};
+use crate::types::*;
+use crate::validated_transaction::*;
+
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// Available funds never go negative, e.g. by charging back funds already withdrawn.
+    AvailableNotNegative,
+    /// An account's held funds equal the amounts of its open disputes.
+    HeldMatchesDisputes,
+    /// An account's total equals what its deposits, withdrawals and chargebacks add up to.
+    TotalMatchesTransactions,
+    /// The totals of all accounts equal deposits minus withdrawals minus chargebacks.
+    SumOfTotalsMatchesTransactions,
+    /// Every posting to the double-entry books debits as much as it credits.
+    PostingBalances,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Invariant::AvailableNotNegative => "available funds must not be negative",
+            Invariant::HeldMatchesDisputes => "held funds must equal the open disputes",
+            Invariant::TotalMatchesTransactions => {
+                "total must equal deposits minus withdrawals minus chargebacks"
+            }
+            Invariant::SumOfTotalsMatchesTransactions => {
+                "the sum of totals must equal deposits minus withdrawals minus chargebacks"
+            }
+            Invariant::PostingBalances => "debits must equal credits",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// The first command found to break an invariant, with the account before and after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    pub invariant: Invariant,
+    pub command: ValidatedTransactionCommand,
+    pub before: Account,
+    pub after: Account,
+    pub expected: MoneySum,
+    pub actual: MoneySum,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, found {} after {:?} took client {} from {} available and {} held to {} available and {} held{}",
+            self.invariant,
+            self.expected,
+            self.actual,
+            self.command,
+            self.command.client_id(),
+            self.before.available,
+            self.before.held,
+            self.after.available,
+            self.after.held,
+            if self.after.locked.is_some() { ", locked" } else { "" }
+        )
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Checks the invariants after every command an engine commits.
+///
+/// Totals are worked out from the commands applied rather than from the account operations,
+/// and held funds from the disputes the engine has open.
+#[derive(Debug, Clone, Default)]
+pub struct InvariantChecker {
+    expected_totals: HashMap<ClientId, MoneySum>,
+    /// The amount of every tx the engine has under dispute.
+    open_disputes: HashMap<TxId, Money>,
+    /// Sum of the open disputes of each client.
+    disputed: HashMap<ClientId, MoneySum>,
+    /// Sum of every account's total, kept up to date from the account changes.
+    sum_of_totals: MoneySum,
+    /// What the sum of totals should be, from the commands.
+    expected_sum_of_totals: MoneySum,
+}
+
+impl InvariantChecker {
+    /// Start from the accounts and transactions an engine already holds.
+    pub fn new<'a>(
+        accounts: impl IntoIterator<Item = (&'a ClientId, &'a Account)>,
+        transactions: impl IntoIterator<Item = (&'a TxId, &'a TransactionState)>,
+    ) -> Self {
+        let mut checker = Self::default();
+        for (client_id, account) in accounts {
+            checker.expected_totals.insert(*client_id, total(account));
+            checker.sum_of_totals += total(account);
+        }
+        checker.expected_sum_of_totals = checker.sum_of_totals;
+        for (tx_id, transaction) in transactions {
+            checker.track_dispute(*tx_id, transaction);
+        }
+        checker
+    }
+
+    /// Account for a command that took an account from `before` to `after` and left the tx it
+    /// acts upon as `transaction`.
+    pub fn check(
+        &mut self,
+        validated_tx: &ValidatedTransactionCommand,
+        before: &Account,
+        after: &Account,
+        transaction: &TransactionState,
+    ) -> Result<(), Box<InvariantViolation>> {
+        let client_id = validated_tx.client_id();
+        let total_change = expected_total_change(validated_tx);
+        let expected_total = self.expected_totals.entry(client_id).or_default();
+        *expected_total += total_change;
+        let expected_total = *expected_total;
+        self.expected_sum_of_totals += total_change;
+        self.sum_of_totals += total(after) - total(before);
+        self.track_dispute(validated_tx.tx_id(), transaction);
+        let expected_held = self.disputed.get(&client_id).copied().unwrap_or_default();
+
+        let violation = |invariant, expected, actual| {
+            Box::new(InvariantViolation {
+                invariant,
+                command: validated_tx.clone(),
+                before: before.clone(),
+                after: after.clone(),
+                expected,
+                actual,
+            })
+        };
+        if after.available.is_negative() {
+            return Err(violation(
+                Invariant::AvailableNotNegative,
+                MoneySum::default(),
+                after.available.into(),
+            ));
+        }
+        if MoneySum::from(after.held) != expected_held {
+            return Err(violation(
+                Invariant::HeldMatchesDisputes,
+                expected_held,
+                after.held.into(),
+            ));
+        }
+        if total(after) != expected_total {
+            return Err(violation(
+                Invariant::TotalMatchesTransactions,
+                expected_total,
+                total(after),
+            ));
+        }
+        if self.sum_of_totals != self.expected_sum_of_totals {
+            return Err(violation(
+                Invariant::SumOfTotalsMatchesTransactions,
+                self.expected_sum_of_totals,
+                self.sum_of_totals,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Follow the dispute state of `tx_id` as the engine recorded it.
+    fn track_dispute(&mut self, tx_id: TxId, transaction: &TransactionState) {
+        let disputed = self.disputed.entry(transaction.client_id).or_default();
+        if let Some(amount) = self.open_disputes.remove(&tx_id) {
+            *disputed -= amount;
+        }
+        if transaction.dispute_state == DisputeState::Disputed {
+            self.open_disputes.insert(tx_id, transaction.amount);
+            *disputed += transaction.amount;
+        }
+    }
+}
+
+/// An account's total, widened to be summed with those of other accounts.
+fn total(account: &Account) -> MoneySum {
+    account.total().into()
+}
+
+/// How a command should change an account's total. Disputes of deposits only move funds into
+/// held, and a chargeback removes a deposit's funds. A disputed withdrawal is credited back to
+/// the client when it is disputed, or when it is charged back if the dispute held the client's
+/// own funds.
+fn expected_total_change(validated_tx: &ValidatedTransactionCommand) -> MoneySum {
+    let zero = MoneySum::default();
+    match validated_tx {
+        ValidatedTransactionCommand::Deposit(deposit) => deposit.amount.into(),
+        ValidatedTransactionCommand::Withdrawal(withdrawal) => -MoneySum::from(withdrawal.amount),
+        ValidatedTransactionCommand::Dispute(dispute) => match dispute.hold {
+            HoldDirection::FromAvailable => zero,
+            HoldDirection::Credit => dispute.amount.into(),
+        },
+        ValidatedTransactionCommand::Resolve(resolve) => match resolve.hold {
+            HoldDirection::FromAvailable => zero,
+            HoldDirection::Credit => -MoneySum::from(resolve.amount),
+        },
+        ValidatedTransactionCommand::Chargeback(chargeback) => {
+            match (chargeback.hold, chargeback.kind) {
+                (HoldDirection::FromAvailable, TransactionKind::Deposit) => {
+                    -MoneySum::from(chargeback.amount)
+                }
+                (HoldDirection::FromAvailable, TransactionKind::Withdrawal) => {
+                    chargeback.amount.into()
+                }
+                (HoldDirection::Credit, _) => zero,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::*;
+    use crate::policy::*;
+    use crate::transaction::*;
+
+    #[test]
+    fn test_invariants_hold() {
+        let mut engine = Engine::new(Policy {
+            operator_client_id: Some(0),
+            ..Default::default()
+        })
+        .with_invariant_checks();
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Deposit, 1, 3, "5.0"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 2, "4.0"),
+            AnyTransaction::new(CommandType::Dispute, 0, 2, ""),
+            AnyTransaction::new(CommandType::Resolve, 0, 2, ""),
+            AnyTransaction::new(CommandType::Dispute, 1, 3, ""),
+            AnyTransaction::new(CommandType::Resolve, 1, 3, ""),
+            AnyTransaction::new(CommandType::Dispute, 0, 2, ""),
+            AnyTransaction::new(CommandType::Chargeback, 0, 2, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(engine.invariant_violation(), None);
+    }
+
+    #[test]
+    fn test_sums_totals_past_money() {
+        // Each total fits in a `Money`, but their sum doesn't.
+        let mut engine = Engine::new(Policy::default()).with_invariant_checks();
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "900000000000000"),
+            AnyTransaction::new(CommandType::Deposit, 2, 2, "900000000000000"),
+            AnyTransaction::new(CommandType::Dispute, 2, 2, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(engine.invariant_violation(), None);
+    }
+
+    #[test]
+    fn test_follows_disputes_from_snapshot() {
+        let mut engine = Engine::new(Policy::default());
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Dispute, 1, 1, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+
+        // The restored dispute is held, so resolving it leaves nothing held.
+        let mut restored =
+            Engine::from_snapshot(Policy::default(), engine.snapshot()).with_invariant_checks();
+        restored
+            .apply(AnyTransaction::new(CommandType::Resolve, 1, 1, ""))
+            .unwrap();
+        assert_eq!(restored.invariant_violation(), None);
+    }
+
+    #[test]
+    fn test_reports_negative_available() {
+        let mut engine = Engine::new(Policy::default()).with_invariant_checks();
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 2, "4.0"),
+            AnyTransaction::new(CommandType::Dispute, 1, 1, ""),
+            AnyTransaction::new(CommandType::Chargeback, 1, 1, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+
+        // The dispute already took available negative, the chargeback isn't reported.
+        let violation = engine.invariant_violation().unwrap();
+        assert_eq!(violation.invariant, Invariant::AvailableNotNegative);
+        assert_eq!(violation.after.available, "-4.0".parse().unwrap());
+        assert_eq!(
+            violation.to_string(),
+            "available funds must not be negative: expected 0.0000, found -4.0000 after \
+             Dispute(ValidDispute { tx_id: 1, raising_client_id: 1, contended_client_id: 1, \
+             amount: Money(100000), hold: FromAvailable }) took client 1 from 6.0000 available \
+             and 0.0000 held to -4.0000 available and 10.0000 held"
+        );
+    }
+
+    #[test]
+    fn test_reports_unexpected_balances() {
+        let before = Account::default();
+        let mut after = Account::default();
+        after.deposit("1.0".parse().unwrap()).unwrap();
+        after.held = "0.5".parse().unwrap();
+        let deposit = ValidatedTransactionCommand::Deposit(ValidDeposit {
+            tx_id: 1,
+            client_id: 1,
+            amount: "1.0".parse().unwrap(),
+        });
+
+        let transaction = TransactionState {
+            client_id: 1,
+            kind: TransactionKind::Deposit,
+            amount: "1.0".parse().unwrap(),
+            dispute_state: DisputeState::Settled,
+        };
+
+        let violation = InvariantChecker::default()
+            .check(&deposit, &before, &after, &transaction)
+            .unwrap_err();
+        assert_eq!(violation.invariant, Invariant::HeldMatchesDisputes);
+        assert_eq!(violation.actual.to_string(), "0.5000");
+    }
+}
@@ -0,0 +1,356 @@
+use crate::account::*;
+use crate::transaction::TransactionKind;
+use crate::types::*;
+use crate::validated_transaction::*;
+
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+
+/// An account of the double-entry books. Each client has an available and a held sub-ledger,
+/// which are liabilities: money owed to the client carries a credit balance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    ClientAvailable(ClientId),
+    ClientHeld(ClientId),
+    /// Money paid in by deposits and out by withdrawals and deposit chargebacks.
+    ExternalFunding,
+    /// Disputed withdrawals credited to held funds, pending the outcome of the dispute.
+    DisputeSuspense,
+    /// Withdrawals refunded to clients by a chargeback.
+    ChargebackLosses,
+    /// Counterpart of the balances an engine was restored with from a snapshot.
+    OpeningBalances,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::ClientAvailable(client_id) => {
+                write!(f, "client:{}:available", client_id)
+            }
+            LedgerAccount::ClientHeld(client_id) => write!(f, "client:{}:held", client_id),
+            LedgerAccount::ExternalFunding => write!(f, "external_funding"),
+            LedgerAccount::DisputeSuspense => write!(f, "dispute_suspense"),
+            LedgerAccount::ChargebackLosses => write!(f, "chargeback_losses"),
+            LedgerAccount::OpeningBalances => write!(f, "opening_balances"),
+        }
+    }
+}
+
+/// One side of a posting. Positive amounts are debits and negative amounts credits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalLine {
+    pub account: LedgerAccount,
+    pub amount: Money,
+}
+
+impl JournalLine {
+    fn debit(account: LedgerAccount, amount: Money) -> Self {
+        Self { account, amount }
+    }
+
+    fn credit(account: LedgerAccount, amount: Money) -> Self {
+        Self {
+            account,
+            amount: -amount,
+        }
+    }
+}
+
+/// The balanced lines a command posts, mirroring what `Engine::execute_command` does to the
+/// client's account.
+pub fn journal_lines(validated_tx: &ValidatedTransactionCommand) -> Vec<JournalLine> {
+    use LedgerAccount::*;
+
+    let client_id = validated_tx.client_id();
+    let available = ClientAvailable(client_id);
+    let held = ClientHeld(client_id);
+    match validated_tx {
+        ValidatedTransactionCommand::Deposit(deposit) => vec![
+            JournalLine::debit(ExternalFunding, deposit.amount),
+            JournalLine::credit(available, deposit.amount),
+        ],
+        ValidatedTransactionCommand::Withdrawal(withdrawal) => vec![
+            JournalLine::debit(available, withdrawal.amount),
+            JournalLine::credit(ExternalFunding, withdrawal.amount),
+        ],
+        ValidatedTransactionCommand::Dispute(dispute) => match dispute.hold {
+            HoldDirection::FromAvailable => vec![
+                JournalLine::debit(available, dispute.amount),
+                JournalLine::credit(held, dispute.amount),
+            ],
+            HoldDirection::Credit => vec![
+                JournalLine::debit(DisputeSuspense, dispute.amount),
+                JournalLine::credit(held, dispute.amount),
+            ],
+        },
+        ValidatedTransactionCommand::Resolve(resolve) => match resolve.hold {
+            HoldDirection::FromAvailable => vec![
+                JournalLine::debit(held, resolve.amount),
+                JournalLine::credit(available, resolve.amount),
+            ],
+            HoldDirection::Credit => vec![
+                JournalLine::debit(held, resolve.amount),
+                JournalLine::credit(DisputeSuspense, resolve.amount),
+            ],
+        },
+        ValidatedTransactionCommand::Chargeback(chargeback) => {
+            match (chargeback.hold, chargeback.kind) {
+                (HoldDirection::FromAvailable, TransactionKind::Deposit) => vec![
+                    JournalLine::debit(held, chargeback.amount),
+                    JournalLine::credit(ExternalFunding, chargeback.amount),
+                ],
+                (HoldDirection::FromAvailable, TransactionKind::Withdrawal) => vec![
+                    JournalLine::debit(held, chargeback.amount),
+                    JournalLine::credit(available, chargeback.amount),
+                    JournalLine::debit(ChargebackLosses, chargeback.amount),
+                    JournalLine::credit(available, chargeback.amount),
+                ],
+                (HoldDirection::Credit, _) => vec![
+                    JournalLine::debit(held, chargeback.amount),
+                    JournalLine::credit(available, chargeback.amount),
+                    JournalLine::debit(ChargebackLosses, chargeback.amount),
+                    JournalLine::credit(DisputeSuspense, chargeback.amount),
+                ],
+            }
+        }
+    }
+}
+
+/// Net balance of every account posted to. Balanced postings keep the total at zero.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TrialBalance {
+    balances: BTreeMap<LedgerAccount, MoneySum>,
+}
+
+/// Returned by `TrialBalance::post` for lines whose debits and credits differ. Nothing is
+/// posted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unbalanced {
+    pub lines: Vec<JournalLine>,
+    /// Debits minus credits.
+    pub off_by: MoneySum,
+}
+
+impl fmt::Display for Unbalanced {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "journal lines {:?} are off by {}",
+            self.lines, self.off_by
+        )
+    }
+}
+
+impl std::error::Error for Unbalanced {}
+
+impl TrialBalance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn post(&mut self, lines: &[JournalLine]) -> Result<(), Unbalanced> {
+        let off_by = lines
+            .iter()
+            .fold(MoneySum::default(), |sum, line| sum + line.amount);
+        if off_by != MoneySum::default() {
+            return Err(Unbalanced {
+                lines: lines.to_vec(),
+                off_by,
+            });
+        }
+        self.add(lines);
+        Ok(())
+    }
+
+    /// Post the balances of accounts that existed before the books were opened.
+    pub fn open<'a>(&mut self, accounts: impl IntoIterator<Item = (&'a ClientId, &'a Account)>) {
+        for (client_id, account) in accounts {
+            self.add(&[
+                JournalLine::credit(
+                    LedgerAccount::ClientAvailable(*client_id),
+                    account.available,
+                ),
+                JournalLine::debit(LedgerAccount::OpeningBalances, account.available),
+                JournalLine::credit(LedgerAccount::ClientHeld(*client_id), account.held),
+                JournalLine::debit(LedgerAccount::OpeningBalances, account.held),
+            ]);
+        }
+    }
+
+    /// Combine the books of shards, which share the system accounts.
+    pub fn merge(&mut self, other: TrialBalance) {
+        for (account, balance) in other.balances {
+            *self.balances.entry(account).or_default() += balance;
+        }
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> MoneySum {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> MoneySum {
+        self.balances
+            .values()
+            .fold(MoneySum::default(), |total, balance| total + *balance)
+    }
+
+    /// Fails unless debits equal credits and every client's sub-ledgers agree with their
+    /// account.
+    pub fn check(&self, accounts: &HashMap<ClientId, Account>) -> Result<()> {
+        if self.total() != MoneySum::default() {
+            return Err(eyre!("The trial balance is off by {}", self.total()));
+        }
+        for (client_id, account) in accounts {
+            let available = -self.balance(LedgerAccount::ClientAvailable(*client_id));
+            let held = -self.balance(LedgerAccount::ClientHeld(*client_id));
+            if (available, held) != (account.available.into(), account.held.into()) {
+                return Err(eyre!(
+                    "The books give client {} {} available and {} held, but the account has {} and {}",
+                    client_id,
+                    available,
+                    held,
+                    account.available,
+                    account.held
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, lines: &[JournalLine]) {
+        for line in lines {
+            *self.balances.entry(line.account).or_default() += line.amount;
+        }
+    }
+
+    /// Written as a csv of debit and credit balances, ending with their totals.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let write = || -> Result<()> {
+            let mut wtr = csv::Writer::from_path(path)?;
+            wtr.write_record(["account", "debit", "credit"])?;
+
+            let zero = MoneySum::default();
+            let (mut debits, mut credits) = (zero, zero);
+            for (account, balance) in &self.balances {
+                let (debit, credit) = if balance.is_negative() {
+                    (zero, -*balance)
+                } else {
+                    (*balance, zero)
+                };
+                debits += debit;
+                credits += credit;
+                wtr.write_record([account.to_string(), debit.to_string(), credit.to_string()])?;
+            }
+            wtr.write_record(["total".to_string(), debits.to_string(), credits.to_string()])?;
+            wtr.flush()?;
+            Ok(())
+        };
+        write().wrap_err_with(|| format!("Failed to write trial balance {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::*;
+    use crate::policy::*;
+    use crate::transaction::*;
+
+    #[test]
+    fn test_trial_balance() -> Result<()> {
+        let mut engine = Engine::new(Policy {
+            operator_client_id: Some(0),
+            ..Default::default()
+        })
+        .with_double_entry();
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Deposit, 2, 2, "5.0"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 3, "4.0"),
+            AnyTransaction::new(CommandType::Dispute, 1, 1, ""),
+            AnyTransaction::new(CommandType::Resolve, 1, 1, ""),
+            AnyTransaction::new(CommandType::Dispute, 0, 3, ""),
+            AnyTransaction::new(CommandType::Chargeback, 0, 3, ""),
+            AnyTransaction::new(CommandType::Dispute, 2, 2, ""),
+            AnyTransaction::new(CommandType::Chargeback, 2, 2, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+
+        let trial_balance = engine.trial_balance().unwrap();
+        trial_balance.check(engine.accounts())?;
+        let balance = |account| trial_balance.balance(account).to_string();
+        // 15 deposited, 4 withdrawn and 5 paid back out by the deposit chargeback.
+        assert_eq!(balance(LedgerAccount::ExternalFunding), "6.0000");
+        assert_eq!(balance(LedgerAccount::ChargebackLosses), "4.0000");
+        assert_eq!(balance(LedgerAccount::DisputeSuspense), "0.0000");
+        assert_eq!(balance(LedgerAccount::ClientAvailable(1)), "-10.0000");
+
+        let mut unbalanced = trial_balance.clone();
+        unbalanced.balances.insert(
+            LedgerAccount::ChargebackLosses,
+            "3.0".parse::<Money>()?.into(),
+        );
+        assert_eq!(
+            unbalanced.check(engine.accounts()).unwrap_err().to_string(),
+            "The trial balance is off by -1.0000"
+        );
+
+        // Books opened on restored accounts balance against the opening balances.
+        let mut restored =
+            Engine::from_snapshot(Policy::default(), engine.snapshot()).with_double_entry();
+        restored
+            .apply(AnyTransaction::new(CommandType::Deposit, 3, 4, "1.0"))
+            .unwrap();
+        let trial_balance = restored.trial_balance().unwrap();
+        trial_balance.check(restored.accounts())?;
+        assert_eq!(
+            trial_balance
+                .balance(LedgerAccount::OpeningBalances)
+                .to_string(),
+            "10.0000"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trial_balance_sums_past_money() -> Result<()> {
+        // Each deposit fits in a `Money`, but external funding pays in more than one can hold.
+        let mut engine = Engine::new(Policy::default()).with_double_entry();
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "900000000000000"),
+            AnyTransaction::new(CommandType::Deposit, 2, 2, "900000000000000"),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+        let trial_balance = engine.trial_balance().unwrap();
+        trial_balance.check(engine.accounts())?;
+        assert_eq!(
+            trial_balance
+                .balance(LedgerAccount::ExternalFunding)
+                .to_string(),
+            "1800000000000000.0000"
+        );
+
+        let mut unbalanced = trial_balance.clone();
+        let lines = [
+            JournalLine::debit(LedgerAccount::ExternalFunding, "2.0".parse()?),
+            JournalLine::credit(LedgerAccount::ClientAvailable(1), "1.0".parse()?),
+        ];
+        assert_eq!(
+            unbalanced.post(&lines).unwrap_err().off_by.to_string(),
+            "1.0000"
+        );
+        assert_eq!(&unbalanced, trial_balance);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use crate::account::*;
+use crate::types::*;
+use crate::validated_transaction::*;
+
+use eyre::*;
+use serde::{Deserialize, Serialize};
+use std::result::Result::Ok;
+use std::{collections::HashMap, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerEntryKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+/// One change to a client's balances, as written to the `--ledger` csv.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Position in the ledger, numbered from 1 when the entry is written.
+    pub sequence: u64,
+    #[serde(rename = "tx")]
+    pub tx_id: TxId,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    pub kind: LedgerEntryKind,
+    pub delta_available: Money,
+    pub delta_held: Money,
+    /// Balances of the account once the change is made.
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+}
+
+impl LedgerEntry {
+    /// The change `validated_tx` made to an account, yet to be numbered. Fails if a delta
+    /// would not fit in a `Money`.
+    pub fn new(
+        validated_tx: &ValidatedTransactionCommand,
+        before: &Account,
+        after: &Account,
+    ) -> Result<Self, AccountError> {
+        let (tx_id, kind) = match validated_tx {
+            ValidatedTransactionCommand::Deposit(deposit) => {
+                (deposit.tx_id, LedgerEntryKind::Deposit)
+            }
+            ValidatedTransactionCommand::Withdrawal(withdrawal) => {
+                (withdrawal.tx_id, LedgerEntryKind::Withdrawal)
+            }
+            ValidatedTransactionCommand::Dispute(dispute) => {
+                (dispute.tx_id, LedgerEntryKind::Dispute)
+            }
+            ValidatedTransactionCommand::Resolve(resolve) => {
+                (resolve.tx_id, LedgerEntryKind::Resolve)
+            }
+            ValidatedTransactionCommand::Chargeback(chargeback) => {
+                (chargeback.tx_id, LedgerEntryKind::Chargeback)
+            }
+        };
+
+        let delta =
+            |after: Money, before: Money| after.checked_sub(before).ok_or(AccountError::Overflow);
+        Ok(Self {
+            sequence: 0,
+            tx_id,
+            client_id: validated_tx.client_id(),
+            kind,
+            delta_available: delta(after.available, before.available)?,
+            delta_held: delta(after.held, before.held)?,
+            available: after.available,
+            held: after.held,
+            total: after.total(),
+            locked: after.locked.is_some(),
+        })
+    }
+}
+
+pub fn read_ledger(path: impl AsRef<Path>) -> Result<Vec<LedgerEntry>> {
+    let path = path.as_ref();
+    let read = || -> Result<Vec<LedgerEntry>> {
+        let mut rdr = csv::Reader::from_path(path)?;
+        Ok(rdr.deserialize().collect::<csv::Result<_>>()?)
+    };
+    read().wrap_err_with(|| format!("Failed to read ledger {}", path.display()))
+}
+
+/// Replay a ledger into the accounts it describes, starting from none. Each client's entries
+/// must come in sequence order. Fails if the deltas don't add up to the recorded balances.
+pub fn rebuild_accounts(
+    entries: impl IntoIterator<Item = LedgerEntry>,
+) -> Result<HashMap<ClientId, Account>> {
+    let mut accounts: HashMap<ClientId, Account> = HashMap::new();
+    for entry in entries {
+        let account = accounts.entry(entry.client_id).or_default();
+        let apply = |balance: Money, delta: Money| {
+            balance.checked_add(delta).ok_or_else(|| {
+                eyre!(
+                    "Ledger entry {} overflows the balances of client {}",
+                    entry.sequence,
+                    entry.client_id
+                )
+            })
+        };
+        account.available = apply(account.available, entry.delta_available)?;
+        account.held = apply(account.held, entry.delta_held)?;
+        if entry.locked {
+            account.locked = Some(Locked {
+                reason_for_lock: LockReason::Chargeback,
+            });
+        }
+
+        if (
+            account.available,
+            account.held,
+            account.available.checked_add(account.held),
+        ) != (entry.available, entry.held, Some(entry.total))
+        {
+            return Err(eyre!(
+                "Ledger entry {} leaves client {} with {} available and {} held, but records {} and {}",
+                entry.sequence,
+                entry.client_id,
+                account.available,
+                account.held,
+                entry.available,
+                entry.held
+            ));
+        }
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::*;
+    use crate::error::*;
+    use crate::policy::*;
+    use crate::transaction::*;
+
+    #[test]
+    fn test_rebuild_accounts_matches_engine() -> Result<()> {
+        let mut engine = Engine::new(Policy {
+            operator_client_id: Some(0),
+            ..Default::default()
+        });
+        let transactions = [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Deposit, 2, 2, "5.0"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 3, "4.0"),
+            AnyTransaction::new(CommandType::Dispute, 1, 1, ""),
+            AnyTransaction::new(CommandType::Resolve, 1, 1, ""),
+            AnyTransaction::new(CommandType::Dispute, 0, 3, ""),
+            AnyTransaction::new(CommandType::Chargeback, 0, 3, ""),
+            AnyTransaction::new(CommandType::Dispute, 2, 2, ""),
+            AnyTransaction::new(CommandType::Chargeback, 2, 2, ""),
+            AnyTransaction::new(CommandType::Withdrawal, 2, 4, "1.0"),
+        ];
+
+        let mut entries = Vec::new();
+        for transaction in transactions {
+            if let Ok(outcome) = engine.apply(transaction) {
+                let mut entry = outcome.ledger_entry;
+                entry.sequence = entries.len() as u64 + 1;
+                entries.push(entry);
+            }
+        }
+        assert_eq!(entries.len(), 9);
+        assert_eq!(entries[3].kind, LedgerEntryKind::Dispute);
+        assert_eq!(entries[3].delta_available, "-10.0".parse()?);
+        assert_eq!(entries[3].delta_held, "10.0".parse()?);
+
+        assert_eq!(&rebuild_accounts(entries.clone())?, engine.accounts());
+
+        entries[4].delta_held = "-9.0".parse()?;
+        let error = rebuild_accounts(entries).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Ledger entry 5 leaves client 1 with 6.0000 available and 1.0000 held, but records 6.0000 and 0.0000"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ledger_deltas_never_overflow() -> Result<()> {
+        // Refunding the withdrawal would take available from -600T to 600T, a change too large
+        // for a `Money`, so the chargeback is rejected rather than recorded.
+        let mut engine = Engine::new(Policy {
+            withdrawal_dispute_hold: HoldDirection::FromAvailable,
+            ..Default::default()
+        });
+        for transaction in [
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "600000000000000"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 2, "600000000000000"),
+            AnyTransaction::new(CommandType::Dispute, 1, 2, ""),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+        let before = engine.account(1).unwrap().clone();
+        assert_eq!(
+            engine.apply(AnyTransaction::new(CommandType::Chargeback, 1, 2, "")),
+            Err(RejectionReason::Account(AccountError::Overflow))
+        );
+        assert_eq!(engine.account(1), Some(&before));
+
+        // A ledger whose deltas overflow fails to rebuild.
+        let outcome = Engine::new(Policy::default())
+            .apply(AnyTransaction::new(
+                CommandType::Deposit,
+                1,
+                1,
+                "900000000000000",
+            ))
+            .unwrap();
+        let entries = [1, 2].map(|sequence| LedgerEntry {
+            sequence,
+            ..outcome.ledger_entry.clone()
+        });
+        assert_eq!(
+            rebuild_accounts(entries).unwrap_err().to_string(),
+            "Ledger entry 2 overflows the balances of client 1"
+        );
+
+        Ok(())
+    }
+}
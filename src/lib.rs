@@ -0,0 +1,24 @@
+//! A toy payment engine. `Engine` applies transactions synchronously, the `handlers` wire it
+//! into a threaded pipeline and `pipeline::process_transactions` runs one.
+
+pub mod account;
+pub mod dispute;
+pub mod engine;
+pub mod error;
+pub mod handlers;
+pub mod invariants;
+pub mod journal;
+pub mod ledger;
+pub mod money;
+pub mod pipeline;
+pub mod policy;
+pub mod report;
+pub mod snapshot;
+pub mod source;
+pub mod transaction;
+mod tx_id_set;
+pub mod types;
+pub mod validated_transaction;
+pub mod wal;
+
+pub use engine::{Engine, Outcome};
@@ -1,125 +1,171 @@
-mod account;
-mod handlers;
-mod transaction;
-mod types;
-mod validated_transaction;
+use kraken::handlers::*;
+use kraken::pipeline::*;
+use kraken::report::*;
+use kraken::source::*;
+use kraken::validated_transaction::HoldDirection;
 
-use handlers::*;
-use transaction::*;
-
-use csv::Writer;
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::{Receiver, Sender};
+use std::io::{self, BufWriter};
+use std::process;
+
+use eyre::{eyre, Result};
 
-use eyre::Result;
+const USAGE: &str = "Usage: cargo run -- <transactions.csv|transactions.jsonl|-|glob>... [--input-format csv|jsonl] [--rejections <rejections.csv>] [--operator-client <client id>] [--withdrawal-dispute-hold credit|from-available] [--no-redispute] [--shards <n>] [--reader-threads <n>] [--channel-capacity <n>] [--snapshot-in <snapshot.json>] [--snapshot-out <snapshot.json>] [--wal <dir>] [--ledger <ledger.csv>] [--trial-balance <trial_balance.csv>] [--check-invariants] [--order client|insertion] [--format csv|json|jsonl]";
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: cargo run -- <transactions.csv>");
-        return Ok(());
-    }
-    let input_filename = args[1].clone();
+    let (source, output_format, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
 
-    process_transactions(input_filename, None)
+    let mut sink = output_format.sink(BufWriter::new(io::stdout()));
+    process_transactions(source, sink.as_mut(), options)?;
+    Ok(())
 }
 
-/// Pass None into output_filename to write to std-out
-pub fn process_transactions(input_filename: String, output_filename: Option<String>) -> Result<()> {
-    let (tx_any_tx, rx_any_tx): (Sender<AnyTransaction>, Receiver<AnyTransaction>) = channel();
-    let (tx_tx_command, rx_tx_command): (Sender<TransactionCommand>, Receiver<TransactionCommand>) =
-        channel();
-
-    let csv_reader = CsvReader::new(tx_any_tx.clone());
-    let csv_reader_handle = csv_reader.start(input_filename.clone(), 1)?;
-
-    let command_converter = CommandConverter::new(rx_any_tx, tx_tx_command.clone());
-    let command_converter_handle = command_converter.start();
-
-    let account_manager = AccountManager::new(rx_tx_command);
-    let account_manager_handle = account_manager.start();
-
-    drop(tx_any_tx);
-    drop(tx_tx_command);
-
-    // TODO: Need to at least eprintln the errors
-    csv_reader_handle.join().unwrap();
-    command_converter_handle.join().unwrap();
-    let accounts = account_manager_handle.join().unwrap();
-
-    let output_file: Box<dyn Write> = match output_filename {
-        Some(ref s) => Box::new(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(s)?,
-        ),
-        None => Box::new(std::io::stdout()),
-    };
+fn parse_args(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(FileSource, ReportFormat, Options)> {
+    let mut input_filenames = Vec::new();
+    let mut output_format = ReportFormat::default();
+    let mut input_format = None;
+    let mut reader_threads = 1;
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--rejections" => options.rejections_filename = Some(value()?),
+            "--input-format" => {
+                input_format = Some(match value()?.as_str() {
+                    "csv" => InputFormat::Csv,
+                    "jsonl" => InputFormat::Jsonl,
+                    other => return Err(eyre!("Unknown input format {}", other)),
+                });
+            }
+            "--operator-client" => {
+                options.policy.operator_client_id = Some(value()?.parse()?);
+            }
+            "--shards" => {
+                options.shard_count = value()?.parse()?;
+                if options.shard_count == 0 {
+                    return Err(eyre!("--shards must be at least 1"));
+                }
+            }
+            "--reader-threads" => {
+                reader_threads = value()?.parse()?;
+                if reader_threads == 0 {
+                    return Err(eyre!("--reader-threads must be at least 1"));
+                }
+            }
+            "--channel-capacity" => {
+                options.channel_capacity = value()?.parse()?;
+                if options.channel_capacity == 0 {
+                    return Err(eyre!("--channel-capacity must be at least 1"));
+                }
+            }
+            "--snapshot-in" => options.snapshot_in = Some(value()?),
+            "--snapshot-out" => options.snapshot_out = Some(value()?),
+            "--wal" => options.wal_dir = Some(value()?),
+            "--ledger" => options.ledger_filename = Some(value()?),
+            "--trial-balance" => options.trial_balance_filename = Some(value()?),
+            "--order" => {
+                options.output_order = match value()?.as_str() {
+                    "client" => OutputOrder::ClientId,
+                    "insertion" => OutputOrder::Insertion,
+                    other => return Err(eyre!("Unknown order {}", other)),
+                };
+            }
+            "--format" => {
+                output_format = match value()?.as_str() {
+                    "csv" => ReportFormat::Csv,
+                    "json" => ReportFormat::Json,
+                    "jsonl" => ReportFormat::Jsonl,
+                    other => return Err(eyre!("Unknown format {}", other)),
+                };
+            }
+            "--check-invariants" => options.check_invariants = true,
+            "--no-redispute" => options.policy.allow_redispute = false,
+            "--withdrawal-dispute-hold" => {
+                options.policy.withdrawal_dispute_hold = match value()?.as_str() {
+                    "credit" => HoldDirection::Credit,
+                    "from-available" => HoldDirection::FromAvailable,
+                    other => return Err(eyre!("Unknown withdrawal dispute hold {}", other)),
+                };
+            }
+            _ if !arg.starts_with("--") => input_filenames.extend(expand_input(arg)?),
+            _ => return Err(eyre!("Unexpected argument {}", arg)),
+        }
+    }
 
-    let mut wtr = Writer::from_writer(output_file);
-    wtr.write_record(["client", "available", "held", "total", "locked"])?;
-
-    for (client_id, account) in accounts {
-        wtr.write_record(&[
-            client_id.to_string(),
-            // TODO: Better decimal handling using decimal crate.
-            format!("{:.4}", account.available),
-            format!("{:.4}", account.held),
-            format!("{:.4}", account.total()),
-            if account.locked.is_some() {
-                "true".to_string()
-            } else {
-                "false".to_string()
-            },
-        ])?;
+    if input_filenames.is_empty() {
+        return Err(eyre!("Missing input file"));
     }
+    let mut source = FileSource::new(input_filenames).with_reader_threads(reader_threads);
+    if let Some(input_format) = input_format {
+        source = source.with_format(input_format);
+    }
+    Ok((source, output_format, options))
+}
 
-    wtr.flush()?;
+/// Expand a glob pattern into the files it matches, in sorted order. Anything else is taken
+/// as a file name, including `-` for stdin.
+fn expand_input(arg: String) -> Result<Vec<String>> {
+    if !arg.contains(['*', '?', '[']) {
+        return Ok(vec![arg]);
+    }
 
-    Ok(())
+    let mut file_names = Vec::new();
+    for path in glob::glob(&arg)? {
+        file_names.push(path?.to_string_lossy().into_owned());
+    }
+    if file_names.is_empty() {
+        return Err(eyre!("No input files match {}", arg));
+    }
+    Ok(file_names)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use eyre::Result;
-    use std::fs::read_to_string;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
 
     #[test]
-    fn test_process_transactions() -> Result<()> {
-        let mut temp_input = NamedTempFile::new().unwrap();
-        writeln!(temp_input, "type,client,tx,amount")?;
-        writeln!(temp_input, "deposit,1,1,10.0")?;
-        writeln!(temp_input, "deposit,2,2,5.0")?;
-        writeln!(temp_input, "deposit,1,3,5.0")?;
-        writeln!(temp_input, "withdrawal,1,4,3.0")?;
-        writeln!(temp_input, "dispute,1,1,")?;
-        writeln!(temp_input, "resolve,1,1,")?;
-        writeln!(temp_input, "dispute,1,3,")?;
-        writeln!(temp_input, "chargeback,1,3,")?;
-
-        let temp_output = NamedTempFile::new().unwrap();
-
-        process_transactions(
-            temp_input.path().to_str().unwrap().to_string(),
-            Some(temp_output.path().to_str().unwrap().to_string()),
-        )?;
-
-        let output_content = read_to_string(temp_output.path())?;
-
-        let expected_output = "\
-client,available,held,total,locked\n\
-1,7.0000,0.0000,7.0000,true\n\
-2,5.0000,0.0000,5.0000,false\n";
-
-        assert_eq!(output_content, expected_output);
+    fn test_parse_args_expands_globs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["2024-01-02.csv", "2024-01-01.csv", "notes.txt"] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        let pattern = format!("{}/*.csv", dir.path().display());
+
+        let args = [
+            "-",
+            pattern.as_str(),
+            "--shards",
+            "2",
+            "--format",
+            "jsonl",
+            "extra.csv",
+        ];
+        let (source, output_format, options) = parse_args(args.iter().map(|s| s.to_string()))?;
+
+        let expected = ["-", "2024-01-01.csv", "2024-01-02.csv", "extra.csv"];
+        let input_names = source.input_names();
+        let names: Vec<&str> = input_names
+            .iter()
+            .map(|f| f.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(names, expected);
+        assert_eq!(options.shard_count, 2);
+        assert_eq!(output_format, ReportFormat::Jsonl);
+
+        let unmatched = format!("{}/*.json", dir.path().display());
+        assert!(parse_args([unmatched].into_iter()).is_err());
 
         Ok(())
     }
@@ -0,0 +1,339 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Neg, Sub, SubAssign},
+    str::FromStr,
+};
+
+/// Number of decimal places a `Money` value is exact to.
+pub const MONEY_DECIMALS: u32 = 4;
+const SCALE: i64 = 10_i64.pow(MONEY_DECIMALS);
+
+/// An exact fixed-point amount stored as ten-thousandths of a unit.
+///
+/// Parsing rejects any input with more than four significant decimal places rather than
+/// rounding it, so that no precision is ever silently lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Money(i64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoneyParseError {
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl fmt::Display for MoneyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyParseError::Empty => write!(f, "amount is empty"),
+            MoneyParseError::InvalidDigit => write!(f, "amount contains an invalid character"),
+            MoneyParseError::TooManyDecimals => {
+                write!(f, "amount has more than {} decimal places", MONEY_DECIMALS)
+            }
+            MoneyParseError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyParseError {}
+
+impl Money {
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+
+        let (whole, fraction) = match unsigned.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (unsigned, ""),
+        };
+
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(MoneyParseError::Empty);
+        }
+        if !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(MoneyParseError::InvalidDigit);
+        }
+
+        // Trailing zeros carry no precision, anything else past 4dp is rejected.
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > MONEY_DECIMALS as usize {
+            return Err(MoneyParseError::TooManyDecimals);
+        }
+
+        let whole_units = whole.bytes().try_fold(0_i64, |acc, b| {
+            acc.checked_mul(10)?.checked_add((b - b'0') as i64)
+        });
+        let fraction_units = fraction
+            .bytes()
+            .chain(std::iter::repeat(b'0'))
+            .take(MONEY_DECIMALS as usize)
+            .fold(0_i64, |acc, b| acc * 10 + (b - b'0') as i64);
+
+        let raw = whole_units
+            .and_then(|w| w.checked_mul(SCALE))
+            .and_then(|w| w.checked_add(fraction_units))
+            .ok_or(MoneyParseError::Overflow)?;
+
+        Ok(Money(if negative { -raw } else { raw }))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_units(f, self.0 as i128)
+    }
+}
+
+/// Write a count of ten-thousandths as a decimal.
+fn write_units(f: &mut fmt::Formatter<'_>, units: i128) -> fmt::Result {
+    let sign = if units < 0 { "-" } else { "" };
+    let abs = units.unsigned_abs();
+    let scale = SCALE as u128;
+    write!(
+        f,
+        "{}{}.{:0width$}",
+        sign,
+        abs / scale,
+        abs % scale,
+        width = MONEY_DECIMALS as usize
+    )
+}
+
+/// Panics on overflow, use `checked_add` where the inputs are untrusted.
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.checked_add(other).expect("Money addition overflowed")
+    }
+}
+
+/// Panics on overflow, use `checked_sub` where the inputs are untrusted.
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.checked_sub(other)
+            .expect("Money subtraction overflowed")
+    }
+}
+
+/// Panics on overflow, which only the most negative value can do.
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(self.0.checked_neg().expect("Money negation overflowed"))
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = *self - other;
+    }
+}
+
+/// A sum of `Money` amounts, e.g. the balance of a ledger account over a whole run. It is wide
+/// enough that no count of amounts a run could add up overflows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MoneySum(i128);
+
+impl MoneySum {
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+}
+
+impl From<Money> for MoneySum {
+    fn from(money: Money) -> Self {
+        MoneySum(money.0 as i128)
+    }
+}
+
+impl fmt::Display for MoneySum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_units(f, self.0)
+    }
+}
+
+impl Add for MoneySum {
+    type Output = MoneySum;
+
+    fn add(self, other: MoneySum) -> MoneySum {
+        MoneySum(self.0 + other.0)
+    }
+}
+
+impl Add<Money> for MoneySum {
+    type Output = MoneySum;
+
+    fn add(self, other: Money) -> MoneySum {
+        self + MoneySum::from(other)
+    }
+}
+
+impl Sub for MoneySum {
+    type Output = MoneySum;
+
+    fn sub(self, other: MoneySum) -> MoneySum {
+        MoneySum(self.0 - other.0)
+    }
+}
+
+impl Sub<Money> for MoneySum {
+    type Output = MoneySum;
+
+    fn sub(self, other: Money) -> MoneySum {
+        self - MoneySum::from(other)
+    }
+}
+
+impl Neg for MoneySum {
+    type Output = MoneySum;
+
+    fn neg(self) -> MoneySum {
+        MoneySum(-self.0)
+    }
+}
+
+impl<T> AddAssign<T> for MoneySum
+where
+    MoneySum: Add<T, Output = MoneySum>,
+{
+    fn add_assign(&mut self, other: T) {
+        *self = *self + other;
+    }
+}
+
+impl<T> SubAssign<T> for MoneySum
+where
+    MoneySum: Sub<T, Output = MoneySum>,
+{
+    fn sub_assign(&mut self, other: T) {
+        *self = *self - other;
+    }
+}
+
+/// Serialized as a decimal string so no precision is lost to floats.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct MoneyVisitor;
+
+        impl Visitor<'_> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a decimal amount with at most {} places", MONEY_DECIMALS)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(MoneyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_money_parse_and_display() {
+        let cases = [
+            ("1", "1.0000"),
+            ("1.5", "1.5000"),
+            ("0.0001", "0.0001"),
+            (".25", "0.2500"),
+            ("10.", "10.0000"),
+            ("1.23450000", "1.2345"),
+            ("-3.1", "-3.1000"),
+            ("-0.0001", "-0.0001"),
+        ];
+
+        for (input, expected) in cases {
+            let money: Money = input.parse().unwrap();
+            assert_eq!(money.to_string(), expected, "input: {}", input);
+        }
+
+        assert_eq!(
+            "1.23456".parse::<Money>(),
+            Err(MoneyParseError::TooManyDecimals)
+        );
+        assert_eq!("1e308".parse::<Money>(), Err(MoneyParseError::InvalidDigit));
+        assert_eq!("".parse::<Money>(), Err(MoneyParseError::Empty));
+        assert_eq!(
+            "999999999999999999".parse::<Money>(),
+            Err(MoneyParseError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_money_checked_arithmetic() {
+        let a: Money = "0.1".parse().unwrap();
+        let b: Money = "0.2".parse().unwrap();
+        assert_eq!(a + b, "0.3".parse().unwrap());
+        assert_eq!(-a, "-0.1".parse().unwrap());
+        let max: Money = "922337203685477.5807".parse().unwrap();
+        let min: Money = "-922337203685477.5807".parse().unwrap();
+        let smallest: Money = "0.0001".parse().unwrap();
+        assert_eq!(max.checked_add(smallest), None);
+        assert_eq!(
+            min.checked_sub(smallest.checked_add(smallest).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_money_sum_does_not_overflow() {
+        let max: Money = "922337203685477.5807".parse().unwrap();
+        let mut sum = MoneySum::default();
+        sum += max;
+        sum += max;
+        assert_eq!(sum.to_string(), "1844674407370955.1614");
+        sum -= MoneySum::from(max);
+        assert_eq!(sum, MoneySum::from(max));
+        assert_eq!((-sum).to_string(), "-922337203685477.5807");
+    }
+}
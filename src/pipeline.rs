@@ -0,0 +1,713 @@
+use crate::account::Account;
+use crate::engine::*;
+use crate::error::*;
+use crate::handlers::*;
+use crate::journal::*;
+use crate::policy::*;
+use crate::report::*;
+use crate::snapshot::*;
+use crate::source::*;
+use crate::transaction::*;
+use crate::types::*;
+use crate::validated_transaction::ValidatedTransactionCommand;
+use crate::wal::*;
+
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+};
+
+/// How the rows of the report are ordered. Either way the report is the same from run to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputOrder {
+    #[default]
+    ClientId,
+    /// The order accounts were opened in, after any restored from a snapshot in client id order.
+    Insertion,
+}
+
+/// Optional settings for a run of `process_transactions`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Pass None to log rejections to std-err.
+    pub rejections_filename: Option<String>,
+    pub policy: Policy,
+    /// Number of account manager threads, clients are partitioned between them.
+    pub shard_count: usize,
+    /// Maximum number of messages buffered between two stages. Together with the number of
+    /// stages and shards this bounds the memory used by in-flight transactions, however large
+    /// the input is.
+    pub channel_capacity: usize,
+    /// State of a previous run to carry on from.
+    pub snapshot_in: Option<String>,
+    /// Where to save the final state for the next run.
+    pub snapshot_out: Option<String>,
+    /// Directory of the write-ahead logs. A run that died part way is resumed by running it
+    /// again with the same inputs and logs.
+    pub wal_dir: Option<String>,
+    /// Where to write every balance change made by the run.
+    pub ledger_filename: Option<String>,
+    /// Keep double-entry books and write their trial balance here, failing the run if it
+    /// doesn't balance.
+    pub trial_balance_filename: Option<String>,
+    /// Check account invariants after every command, failing on the first one broken.
+    pub check_invariants: bool,
+    pub output_order: OutputOrder,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            rejections_filename: None,
+            policy: Policy::default(),
+            shard_count: 1,
+            channel_capacity: 1024,
+            snapshot_in: None,
+            snapshot_out: None,
+            wal_dir: None,
+            ledger_filename: None,
+            trial_balance_filename: None,
+            check_invariants: false,
+            output_order: OutputOrder::default(),
+        }
+    }
+}
+
+/// An account manager shard as it was left by the previous run.
+struct RestoredShard {
+    engine: Engine,
+    wal: Option<Wal>,
+    /// File and line of the last entry in the shard's wal.
+    resume_after: Option<(String, u64)>,
+    /// Accounts opened by the entries of the wal.
+    openings: Vec<AccountOpening>,
+}
+
+/// Split the input snapshot between the shards, then replay each shard's wal on top.
+fn restore_shards(input_filenames: &[String], options: &Options) -> Result<Vec<RestoredShard>> {
+    let snapshot = match options.snapshot_in {
+        Some(ref path) => Snapshot::read(path)?,
+        None => Snapshot::default(),
+    };
+    if options.wal_dir.is_some() {
+        let mut seen = HashSet::new();
+        if let Some(file) = input_filenames.iter().find(|file| !seen.insert(*file)) {
+            return Err(eyre!("{} is given twice, which --wal can't resume", file));
+        }
+    }
+
+    let mut shards = Vec::with_capacity(options.shard_count);
+    for (shard, shard_snapshot) in snapshot.split(options.shard_count).into_iter().enumerate() {
+        let mut engine = Engine::from_snapshot(options.policy.clone(), shard_snapshot);
+        if options.trial_balance_filename.is_some() {
+            engine = engine.with_double_entry();
+        }
+        if options.check_invariants {
+            engine = engine.with_invariant_checks();
+        }
+        let mut resume_after = None;
+        let mut openings = Vec::new();
+        let wal = match options.wal_dir {
+            Some(ref dir) => Some(Wal::open(dir, shard, options.shard_count, |entry| {
+                if let WalRecord::Applied(ValidatedTransactionCommand::Deposit(deposit)) =
+                    &entry.record
+                {
+                    if engine.account(deposit.client_id).is_none() {
+                        openings.push(AccountOpening {
+                            client_id: deposit.client_id,
+                            file: entry.file.as_str().into(),
+                            line: entry.line,
+                        });
+                    }
+                }
+                entry.record.replay(&mut engine).map_err(|reason| {
+                    eyre!("Failed to replay {}:{}: {}", entry.file, entry.line, reason)
+                })?;
+                resume_after = Some((entry.file, entry.line));
+                Ok(())
+            })?),
+            None => None,
+        };
+        if let Some(violation) = engine.invariant_violation() {
+            return Err(
+                eyre::Report::new(violation.clone()).wrap_err("Invariant broken by the wal")
+            );
+        }
+        shards.push(RestoredShard {
+            engine,
+            wal,
+            resume_after,
+            openings,
+        });
+    }
+    Ok(shards)
+}
+
+/// Sort the accounts for the report. Openings are placed by the position of their row in the
+/// inputs, an input given twice counts as its first occurrence.
+fn order_accounts<'a>(
+    accounts: &'a HashMap<ClientId, Account>,
+    order: OutputOrder,
+    input_filenames: &[String],
+    openings: &[AccountOpening],
+) -> Vec<(ClientId, &'a Account)> {
+    let mut accounts: Vec<(ClientId, &Account)> = accounts
+        .iter()
+        .map(|(client_id, account)| (*client_id, account))
+        .collect();
+    match order {
+        OutputOrder::ClientId => accounts.sort_unstable_by_key(|(client_id, _)| *client_id),
+        OutputOrder::Insertion => {
+            let mut input_index = HashMap::new();
+            for (index, file) in input_filenames.iter().enumerate().rev() {
+                input_index.insert(file.as_str(), index);
+            }
+            let opened_at: HashMap<ClientId, (usize, u64)> = openings
+                .iter()
+                .map(|opening| {
+                    let index = input_index
+                        .get(&*opening.file)
+                        .copied()
+                        .unwrap_or(usize::MAX);
+                    (opening.client_id, (index, opening.line))
+                })
+                .collect();
+            // Restored accounts have no opening so they sort first.
+            accounts.sort_unstable_by_key(|(client_id, _)| (opened_at.get(client_id), *client_id));
+        }
+    }
+    accounts
+}
+
+/// The source's inputs are processed in order as one stream, and the final accounts are handed
+/// to `sink`.
+/// Fails if an input can't be opened or any stage errors or panics, in which case nothing is
+/// handed to the sink.
+pub fn process_transactions(
+    source: impl TransactionSource,
+    sink: &mut dyn ReportSink,
+    options: Options,
+) -> Result<Vec<StageSummary>> {
+    let input_names = source.input_names();
+    let shards = restore_shards(&input_names, &options)?;
+    let mut snapshot = Snapshot::default();
+    for shard in &shards {
+        snapshot.merge(shard.engine.snapshot());
+    }
+    let resume_positions = shards.iter().map(|s| s.resume_after.clone()).collect();
+
+    let (tx_any_tx, rx_any_tx): (
+        SyncSender<Sourced<AnyTransaction>>,
+        Receiver<Sourced<AnyTransaction>>,
+    ) = sync_channel(options.channel_capacity);
+    let (tx_tx_command, rx_tx_command): (
+        SyncSender<Sourced<TransactionCommand>>,
+        Receiver<Sourced<TransactionCommand>>,
+    ) = sync_channel(options.channel_capacity);
+    let (tx_rejection, rx_rejection): (SyncSender<Rejection>, Receiver<Rejection>) =
+        sync_channel(options.channel_capacity);
+
+    let rejection_sink = RejectionSink::new(rx_rejection);
+    let rejection_sink_handle = rejection_sink.start(options.rejections_filename)?;
+
+    let (tx_ledger, ledger_sink_handle) = match options.ledger_filename {
+        Some(ref path) => {
+            let (tx_ledger, rx_ledger) = sync_channel(options.channel_capacity);
+            (
+                Some(tx_ledger),
+                Some(LedgerSink::new(rx_ledger).start(path.clone())?),
+            )
+        }
+        None => (None, None),
+    };
+
+    let reader_handle = source.start(tx_any_tx.clone(), tx_rejection.clone())?;
+
+    let command_converter =
+        CommandConverter::new(rx_any_tx, tx_tx_command.clone(), tx_rejection.clone());
+    let command_converter_handle = command_converter.start();
+
+    let mut shard_txs = Vec::with_capacity(options.shard_count);
+    let mut account_manager_handles = Vec::with_capacity(options.shard_count);
+    let mut openings = Vec::new();
+    for shard in shards {
+        openings.extend(shard.openings);
+        let (tx_shard, rx_shard): (
+            SyncSender<Sourced<TransactionCommand>>,
+            Receiver<Sourced<TransactionCommand>>,
+        ) = sync_channel(options.channel_capacity);
+        let mut account_manager = AccountManager::new(rx_shard, tx_rejection.clone(), shard.engine);
+        if let Some(wal) = shard.wal {
+            account_manager = account_manager.with_wal(wal);
+        }
+        if let Some(ref tx_ledger) = tx_ledger {
+            account_manager = account_manager.with_ledger(tx_ledger.clone());
+        }
+        account_manager_handles.push(account_manager.start());
+        shard_txs.push(tx_shard);
+    }
+
+    let router = Router::new(rx_tx_command, shard_txs, tx_rejection.clone())
+        .with_snapshot(&snapshot)
+        .with_resume(&input_names, resume_positions)?;
+    drop(snapshot);
+    let router_handle = router.start();
+
+    drop(tx_any_tx);
+    drop(tx_tx_command);
+    drop(tx_rejection);
+    drop(tx_ledger);
+
+    // Every thread is joined before reporting, so a failing stage never leaves others running.
+    let mut summaries = Vec::new();
+    let mut results = vec![
+        join_thread(Stage::Reader.as_str(), reader_handle).map(|s| summaries.push(s)),
+        join_thread(Stage::CommandConverter.as_str(), command_converter_handle)
+            .map(|s| summaries.push(s)),
+        join_thread(Stage::Router.as_str(), router_handle).map(|s| summaries.push(s)),
+    ];
+    let mut engines = Vec::with_capacity(options.shard_count);
+    for handle in account_manager_handles {
+        results.push(
+            join_thread(Stage::AccountManager.as_str(), handle).map(|shard_output| {
+                summaries.push(shard_output.summary);
+                engines.push(shard_output.engine);
+                openings.extend(shard_output.openings);
+            }),
+        );
+    }
+    results.push(join_thread("rejection_sink", rejection_sink_handle).map(|_| ()));
+    if let Some(handle) = ledger_sink_handle {
+        results.push(join_thread("ledger_sink", handle).map(|_| ()));
+    }
+    results.into_iter().collect::<Result<()>>()?;
+
+    if let Some(ref path) = options.snapshot_out {
+        let mut snapshot = Snapshot::default();
+        for engine in &engines {
+            snapshot.merge(engine.snapshot());
+        }
+        snapshot.write(path)?;
+    }
+
+    let mut trial_balance = TrialBalance::new();
+    for engine in &engines {
+        if let Some(shard_trial_balance) = engine.trial_balance() {
+            trial_balance.merge(shard_trial_balance.clone());
+        }
+    }
+
+    // Shards own disjoint sets of clients so their accounts never overlap.
+    let mut accounts = HashMap::new();
+    for engine in engines {
+        accounts.extend(engine.into_accounts());
+    }
+
+    if let Some(ref path) = options.trial_balance_filename {
+        trial_balance.write(path)?;
+        trial_balance.check(&accounts)?;
+    }
+
+    sink.report(&order_accounts(
+        &accounts,
+        options.output_order,
+        &input_names,
+        &openings,
+    ))?;
+
+    Ok(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validated_transaction::HoldDirection;
+    use std::fs::read_to_string;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn csv_report(sink: CsvSink<Vec<u8>>) -> Result<String> {
+        Ok(String::from_utf8(sink.into_inner()?)?)
+    }
+
+    #[test]
+    fn test_process_transactions() -> Result<()> {
+        let transactions = vec![
+            AnyTransaction::new(CommandType::Deposit, 1, 1, "10.0"),
+            AnyTransaction::new(CommandType::Deposit, 2, 2, "5.0"),
+            AnyTransaction::new(CommandType::Deposit, 1, 3, "5.0"),
+            AnyTransaction::new(CommandType::Withdrawal, 1, 4, "3.0"),
+            AnyTransaction::new(CommandType::Dispute, 1, 1, ""),
+            AnyTransaction::new(CommandType::Resolve, 1, 1, ""),
+            AnyTransaction::new(CommandType::Dispute, 1, 3, ""),
+            AnyTransaction::new(CommandType::Chargeback, 1, 3, ""),
+        ];
+
+        let mut sink = CsvSink::new(Vec::new());
+
+        let summaries = process_transactions(transactions, &mut sink, Options::default())?;
+
+        assert_eq!(summaries.len(), 4);
+        assert!(summaries.iter().all(|s| s.accepted == 8 && s.rejected == 0));
+
+        let output_content = csv_report(sink)?;
+
+        let expected_output = "\
+client,available,held,total,locked\n\
+1,7.0000,0.0000,7.0000,true\n\
+2,5.0000,0.0000,5.0000,false\n";
+
+        assert_eq!(output_content, expected_output);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_transactions_reads_jsonl() -> Result<()> {
+        let mut temp_input = tempfile::Builder::new().suffix(".jsonl").tempfile()?;
+        writeln!(
+            temp_input,
+            r#"{{"type": "deposit", "client": 1, "tx": 1, "amount": "10.0"}}"#
+        )?;
+        writeln!(
+            temp_input,
+            r#"{{"type": "withdrawal", "client": 1, "tx": 2, "amount": 2.5}}"#
+        )?;
+        writeln!(temp_input, r#"{{"type": "dispute", "client": 1, "tx": 1}}"#)?;
+        writeln!(temp_input, "deposit,1,3,1.0")?;
+        let mut sink = CsvSink::new(Vec::new());
+        let temp_rejections = NamedTempFile::new()?;
+
+        process_transactions(
+            FileSource::new(vec![temp_input.path().to_str().unwrap().to_string()]),
+            &mut sink,
+            Options {
+                rejections_filename: Some(temp_rejections.path().to_str().unwrap().to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(
+            csv_report(sink)?,
+            "client,available,held,total,locked\n1,-2.5000,10.0000,7.5000,false\n"
+        );
+        let rejections = read_to_string(temp_rejections.path())?;
+        let rejected: Vec<&str> = rejections.lines().skip(1).collect();
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].starts_with(&format!(
+            "{},4,reader,malformed_row,",
+            temp_input.path().display()
+        )));
+
+        // The flag overrides the extension.
+        let mut sink = CsvSink::new(Vec::new());
+        let result = process_transactions(
+            FileSource::new(vec![temp_input.path().to_str().unwrap().to_string()])
+                .with_format(InputFormat::Csv),
+            &mut sink,
+            Options {
+                rejections_filename: Some(temp_rejections.path().to_str().unwrap().to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(csv_report(sink)?, "client,available,held,total,locked\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_transactions_fails_on_missing_input() {
+        let mut sink = MemorySink::new();
+
+        let error = process_transactions(
+            FileSource::new(vec!["does-not-exist.csv".to_string()]),
+            &mut sink,
+            Options::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Failed to open input file does-not-exist.csv"
+        );
+        assert!(sink.accounts.is_empty() && !sink.finished);
+    }
+
+    #[test]
+    fn test_output_order() -> Result<()> {
+        let write_input = |rows: &[&str]| -> Result<NamedTempFile> {
+            let mut temp_input = NamedTempFile::new()?;
+            writeln!(temp_input, "type,client,tx,amount\n{}", rows.join("\n"))?;
+            Ok(temp_input)
+        };
+        // Client 7's first deposit is rejected, so its account opens on the second file.
+        let day_1 = write_input(&[
+            "deposit,5,1,1.0",
+            "deposit,7,2,-1.0",
+            "deposit,20,3,1.0",
+            "withdrawal,5,4,1.0",
+            "deposit,9,5,1.0",
+        ])?;
+        let day_2 = write_input(&["deposit,7,6,1.0", "deposit,1,7,1.0", "deposit,5,8,1.0"])?;
+
+        let run = |output_order, shard_count| -> Result<Vec<ClientId>> {
+            let mut sink = MemorySink::new();
+            process_transactions(
+                FileSource::new(vec![
+                    day_1.path().to_str().unwrap().to_string(),
+                    day_2.path().to_str().unwrap().to_string(),
+                ]),
+                &mut sink,
+                Options {
+                    rejections_filename: Some("/dev/null".to_string()),
+                    shard_count,
+                    output_order,
+                    ..Default::default()
+                },
+            )?;
+            Ok(sink
+                .accounts
+                .iter()
+                .map(|(client_id, _)| *client_id)
+                .collect())
+        };
+
+        for shard_count in [1, 3] {
+            assert_eq!(run(OutputOrder::ClientId, shard_count)?, [1, 5, 7, 9, 20]);
+            assert_eq!(run(OutputOrder::Insertion, shard_count)?, [5, 20, 9, 7, 1]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_invariants_reports_first_violation() -> Result<()> {
+        let mut temp_input = NamedTempFile::new()?;
+        writeln!(temp_input, "type,client,tx,amount")?;
+        writeln!(temp_input, "deposit,1,1,10.0")?;
+        writeln!(temp_input, "withdrawal,1,2,8.0")?;
+        writeln!(temp_input, "dispute,1,1,")?;
+        writeln!(temp_input, "chargeback,1,1,")?;
+        let input_filename = temp_input.path().to_str().unwrap().to_string();
+
+        let run = |check_invariants| {
+            process_transactions(
+                FileSource::new(vec![input_filename.clone()]),
+                &mut MemorySink::new(),
+                Options {
+                    check_invariants,
+                    ..Default::default()
+                },
+            )
+        };
+
+        run(false)?;
+        let error = run(true).unwrap_err();
+        let causes: Vec<String> = error.chain().map(|e| e.to_string()).collect();
+        assert_eq!(
+            causes[1],
+            format!("Invariant broken by {}:4", input_filename)
+        );
+        assert!(causes[2].starts_with(
+            "available funds must not be negative: expected 0.0000, found -8.0000 after Dispute"
+        ));
+
+        Ok(())
+    }
+
+    fn sorted_lines(report: &str) -> Vec<String> {
+        let mut lines: Vec<String> = report.lines().map(str::to_string).collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_sharded_output_matches_single_threaded() -> Result<()> {
+        let transactions: Vec<AnyTransaction> =
+            GeneratedTransactions::new(7).take(20_000).collect();
+
+        // Client 0 acts as an operator so disputes regularly target another shard's tx.
+        let policy = Policy {
+            operator_client_id: Some(0),
+            ..Default::default()
+        };
+
+        let run = |shard_count| -> Result<String> {
+            let mut sink = CsvSink::new(Vec::new());
+            process_transactions(
+                IterSource::new("generated", transactions.clone()),
+                &mut sink,
+                Options {
+                    rejections_filename: Some("/dev/null".to_string()),
+                    policy: policy.clone(),
+                    shard_count,
+                    ..Default::default()
+                },
+            )?;
+            csv_report(sink)
+        };
+
+        let mut engine = Engine::new(policy.clone());
+        for tx in transactions.clone() {
+            let _ = engine.apply(tx);
+        }
+        let mut expected = "client,available,held,total,locked\n".to_string();
+        let accounts: std::collections::BTreeMap<_, _> = engine.accounts().iter().collect();
+        for (client_id, account) in accounts {
+            expected.push_str(&format!(
+                "{},{},{},{},{}\n",
+                client_id,
+                account.available,
+                account.held,
+                account.total(),
+                account.locked.is_some()
+            ));
+        }
+
+        // Reports are identical byte for byte whatever the shard count.
+        assert_eq!(run(1)?, expected);
+        for shard_count in [2, 3, 8] {
+            assert_eq!(run(shard_count)?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ledger_rebuilds_report() -> Result<()> {
+        let mut sink = CsvSink::new(Vec::new());
+        let temp_ledger = NamedTempFile::new()?;
+
+        let summaries = process_transactions(
+            IterSource::generated(20_000, 5),
+            &mut sink,
+            Options {
+                rejections_filename: Some("/dev/null".to_string()),
+                policy: Policy {
+                    operator_client_id: Some(0),
+                    ..Default::default()
+                },
+                shard_count: 3,
+                ledger_filename: Some(temp_ledger.path().to_str().unwrap().to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        let entries = crate::ledger::read_ledger(temp_ledger.path())?;
+        let accepted: u64 = summaries[3..].iter().map(|s| s.accepted).sum();
+        assert_eq!(entries.len() as u64, accepted);
+        assert!(entries
+            .iter()
+            .enumerate()
+            .all(|(i, entry)| entry.sequence == i as u64 + 1));
+
+        let mut rebuilt = vec!["client,available,held,total,locked".to_string()];
+        for (client_id, account) in crate::ledger::rebuild_accounts(entries)? {
+            rebuilt.push(format!(
+                "{},{},{},{},{}",
+                client_id,
+                account.available,
+                account.held,
+                account.total(),
+                account.locked.is_some()
+            ));
+        }
+        rebuilt.sort();
+
+        assert_eq!(sorted_lines(&csv_report(sink)?), rebuilt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trial_balance_balances() -> Result<()> {
+        for withdrawal_dispute_hold in [HoldDirection::Credit, HoldDirection::FromAvailable] {
+            let mut sink = CsvSink::new(Vec::new());
+            let temp_trial_balance = NamedTempFile::new()?;
+            process_transactions(
+                IterSource::generated(20_000, 13),
+                &mut sink,
+                Options {
+                    rejections_filename: Some("/dev/null".to_string()),
+                    policy: Policy {
+                        operator_client_id: Some(0),
+                        withdrawal_dispute_hold,
+                        ..Default::default()
+                    },
+                    shard_count: 3,
+                    trial_balance_filename: Some(
+                        temp_trial_balance.path().to_str().unwrap().to_string(),
+                    ),
+                    ..Default::default()
+                },
+            )?;
+
+            let trial_balance = read_to_string(temp_trial_balance.path())?;
+            let total: Vec<&str> = trial_balance.lines().last().unwrap().split(',').collect();
+            assert_eq!(total[0], "total");
+            assert_eq!(total[1], total[2]);
+            assert!(trial_balance.contains("\nexternal_funding,"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_resume_matches_combined_run() -> Result<()> {
+        // The second day disputes, resolves, charges back and replays the first day's txs.
+        let combined: Vec<AnyTransaction> = GeneratedTransactions::new(11).take(10_000).collect();
+        let (day_1, day_2) = combined.split_at(5_000);
+        let dir = tempfile::tempdir()?;
+
+        let run = |input: &[AnyTransaction], options: Options| -> Result<String> {
+            let mut sink = CsvSink::new(Vec::new());
+            process_transactions(
+                input.to_vec(),
+                &mut sink,
+                Options {
+                    rejections_filename: Some("/dev/null".to_string()),
+                    policy: Policy {
+                        operator_client_id: Some(0),
+                        ..Default::default()
+                    },
+                    ..options
+                },
+            )?;
+            csv_report(sink)
+        };
+
+        let expected = sorted_lines(&run(&combined, Options::default())?);
+
+        // Snapshots are independent of the shard count they were taken or restored with.
+        for (shards_day_1, shards_day_2) in [(1, 1), (1, 3), (4, 2)] {
+            let snapshot = dir
+                .path()
+                .join(format!("{}-{}.json", shards_day_1, shards_day_2));
+            let snapshot = snapshot.to_str().unwrap().to_string();
+
+            run(
+                day_1,
+                Options {
+                    shard_count: shards_day_1,
+                    snapshot_out: Some(snapshot.clone()),
+                    ..Default::default()
+                },
+            )?;
+            let resumed = run(
+                day_2,
+                Options {
+                    shard_count: shards_day_2,
+                    snapshot_in: Some(snapshot),
+                    ..Default::default()
+                },
+            )?;
+
+            assert_eq!(sorted_lines(&resumed), expected);
+        }
+
+        Ok(())
+    }
+}
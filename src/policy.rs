@@ -0,0 +1,24 @@
+use crate::types::*;
+use crate::validated_transaction::HoldDirection;
+
+/// Configurable business rules for the account manager.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// A client id reserved for operators, who may raise disputes, resolves and chargebacks
+    /// against any client's transactions. When None only the owning client may do so.
+    pub operator_client_id: Option<ClientId>,
+    /// How funds are held when a withdrawal is disputed. Deposits always hold from available.
+    pub withdrawal_dispute_hold: HoldDirection,
+    /// Whether a tx whose dispute was resolved may be disputed again.
+    pub allow_redispute: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            operator_client_id: None,
+            withdrawal_dispute_hold: HoldDirection::default(),
+            allow_redispute: true,
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::account::*;
+use crate::types::*;
+
+use eyre::*;
+use serde::Serialize;
+use std::io::Write;
+use std::result::Result::Ok;
+
+/// Receives the final accounts of a run, in report order, once every stage has finished.
+///
+/// The whole set is handed to `report`, which by default feeds it through the hooks: `begin`,
+/// then `account` once per account, then `finish`. Accounts are not reported as they change.
+pub trait ReportSink {
+    fn report(&mut self, accounts: &[(ClientId, &Account)]) -> Result<()> {
+        self.begin()?;
+        for (client_id, account) in accounts {
+            self.account(*client_id, account)?;
+        }
+        self.finish()
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn account(&mut self, client_id: ClientId, account: &Account) -> Result<()>;
+
+    /// Called once every account has been received, e.g. to flush.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How the final balances are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    /// `client,available,held,total,locked`, with the lock as a bool.
+    #[default]
+    Csv,
+    /// A single array of account objects.
+    Json,
+    /// One account object per line.
+    Jsonl,
+}
+
+impl ReportFormat {
+    /// A sink writing this format to `output`.
+    pub fn sink(self, output: impl Write + 'static) -> Box<dyn ReportSink> {
+        match self {
+            ReportFormat::Csv => Box::new(CsvSink::new(output)),
+            ReportFormat::Json => Box::new(JsonSink::array(output)),
+            ReportFormat::Jsonl => Box::new(JsonSink::lines(output)),
+        }
+    }
+}
+
+pub struct CsvSink<W: Write> {
+    wtr: csv::Writer<W>,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            wtr: csv::Writer::from_writer(output),
+        }
+    }
+
+    pub fn into_inner(self) -> Result<W> {
+        self.wtr.into_inner().map_err(|e| e.into_error().into())
+    }
+}
+
+impl<W: Write> ReportSink for CsvSink<W> {
+    fn begin(&mut self) -> Result<()> {
+        self.wtr
+            .write_record(["client", "available", "held", "total", "locked"])?;
+        Ok(())
+    }
+
+    fn account(&mut self, client_id: ClientId, account: &Account) -> Result<()> {
+        self.wtr.write_record(&[
+            client_id.to_string(),
+            account.available.to_string(),
+            account.held.to_string(),
+            account.total().to_string(),
+            account.locked.is_some().to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// An account as written by the json formats. Amounts are exact decimal strings.
+#[derive(Debug, Serialize)]
+struct AccountRow {
+    client: ClientId,
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+    lock_reason: Option<&'static str>,
+}
+
+impl AccountRow {
+    fn new(client_id: ClientId, account: &Account) -> Self {
+        Self {
+            client: client_id,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked.is_some(),
+            lock_reason: account
+                .locked
+                .as_ref()
+                .map(|locked| locked.reason_for_lock.code()),
+        }
+    }
+}
+
+/// Writes accounts as json objects, including the lock reason. Pass a buffered writer, each
+/// account is a separate write.
+pub struct JsonSink<W: Write> {
+    output: W,
+    /// One object per line rather than a single array.
+    lines: bool,
+    written: u64,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn array(output: W) -> Self {
+        Self {
+            output,
+            lines: false,
+            written: 0,
+        }
+    }
+
+    pub fn lines(output: W) -> Self {
+        Self {
+            output,
+            lines: true,
+            written: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> ReportSink for JsonSink<W> {
+    fn begin(&mut self) -> Result<()> {
+        if !self.lines {
+            self.output.write_all(b"[")?;
+        }
+        Ok(())
+    }
+
+    fn account(&mut self, client_id: ClientId, account: &Account) -> Result<()> {
+        if !self.lines && self.written > 0 {
+            self.output.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.output, &AccountRow::new(client_id, account))?;
+        if self.lines {
+            self.output.write_all(b"\n")?;
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.lines {
+            self.output.write_all(b"]\n")?;
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps a copy of the accounts, e.g. for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    pub accounts: Vec<(ClientId, Account)>,
+    /// Whether the whole report has been received.
+    pub finished: bool,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReportSink for MemorySink {
+    fn begin(&mut self) -> Result<()> {
+        self.accounts.clear();
+        self.finished = false;
+        Ok(())
+    }
+
+    fn account(&mut self, client_id: ClientId, account: &Account) -> Result<()> {
+        self.accounts.push((client_id, account.clone()));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+        Ok(())
+    }
+}
+
+/// Hands the report to several sinks, in turn. Stops at the first that fails.
+#[derive(Default)]
+pub struct MultiSink<'a> {
+    sinks: Vec<&'a mut dyn ReportSink>,
+}
+
+impl<'a> MultiSink<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: &'a mut dyn ReportSink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+}
+
+impl ReportSink for MultiSink<'_> {
+    fn report(&mut self, accounts: &[(ClientId, &Account)]) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.report(accounts)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.begin()?;
+        }
+        Ok(())
+    }
+
+    fn account(&mut self, client_id: ClientId, account: &Account) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.account(client_id, account)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_sinks() -> Result<()> {
+        let mut open = Account::default();
+        open.deposit("1.5".parse()?)?;
+        let mut locked = Account::default();
+        locked.deposit("0.0001".parse()?)?;
+        locked.locked = Some(Locked {
+            reason_for_lock: LockReason::Chargeback,
+        });
+        let accounts = [(1, &open), (2, &locked)];
+
+        let mut csv = CsvSink::new(Vec::new());
+        let mut json = JsonSink::array(Vec::new());
+        let mut jsonl = JsonSink::lines(Vec::new());
+        let mut memory = MemorySink::new();
+        MultiSink::new()
+            .with_sink(&mut csv)
+            .with_sink(&mut json)
+            .with_sink(&mut jsonl)
+            .with_sink(&mut memory)
+            .report(&accounts)?;
+
+        assert_eq!(
+            String::from_utf8(csv.into_inner()?)?,
+            "\
+client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,0.0001,0.0000,0.0001,true
+"
+        );
+
+        let first = r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false,"lock_reason":null}"#;
+        let second = r#"{"client":2,"available":"0.0001","held":"0.0000","total":"0.0001","locked":true,"lock_reason":"chargeback"}"#;
+        assert_eq!(
+            String::from_utf8(json.into_inner())?,
+            format!("[{},{}]\n", first, second)
+        );
+        assert_eq!(
+            String::from_utf8(jsonl.into_inner())?,
+            format!("{}\n{}\n", first, second)
+        );
+
+        assert!(memory.finished);
+        assert_eq!(memory.accounts, vec![(1, open), (2, locked)]);
+
+        let mut empty = JsonSink::array(Vec::new());
+        empty.report(&[])?;
+        assert_eq!(String::from_utf8(empty.into_inner())?, "[]\n");
+
+        Ok(())
+    }
+}
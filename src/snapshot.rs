@@ -0,0 +1,171 @@
+use crate::account::*;
+use crate::handlers::shard_for;
+use crate::transaction::*;
+use crate::types::*;
+
+use eyre::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+use tempfile::NamedTempFile;
+
+/// Bumped whenever the layout of `Snapshot` changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Everything an `Engine` needs to carry on where a previous run stopped, stored as json.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub accounts: BTreeMap<ClientId, Account>,
+    /// Deposits and withdrawals with their dispute state, so they stay disputable.
+    pub transactions: BTreeMap<TxId, TransactionState>,
+    /// Ids claimed by deposits and withdrawals that were rejected, which may never be reused.
+    pub claimed_tx_ids: Vec<TxId>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            accounts: BTreeMap::new(),
+            transactions: BTreeMap::new(),
+            claimed_tx_ids: Vec::new(),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn read(path: impl AsRef<Path>) -> Result<Snapshot> {
+        let path = path.as_ref();
+        let read = || -> Result<Snapshot> {
+            let value: serde_json::Value =
+                serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            // Checked before reading the rest, which a different version may not match.
+            let version = value
+                .get("version")
+                .ok_or_else(|| eyre!("Missing snapshot version"))?;
+            let version = u32::deserialize(version)?;
+            if version != SNAPSHOT_VERSION {
+                return Err(eyre!(
+                    "Unsupported snapshot version {}, expected {}",
+                    version,
+                    SNAPSHOT_VERSION
+                ));
+            }
+            Ok(serde_json::from_value(value)?)
+        };
+        read().wrap_err_with(|| format!("Failed to read snapshot {}", path.display()))
+    }
+
+    /// Written to a temporary file first so a failed run never leaves a truncated snapshot.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let write = || -> Result<()> {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let mut file = NamedTempFile::new_in(dir)?;
+            let mut wtr = BufWriter::new(file.as_file_mut());
+            serde_json::to_writer(&mut wtr, self)?;
+            wtr.flush()?;
+            drop(wtr);
+            file.persist(path)?;
+            Ok(())
+        };
+        write().wrap_err_with(|| format!("Failed to write snapshot {}", path.display()))
+    }
+
+    /// Combine the snapshots of shards, which own disjoint clients and transactions.
+    pub fn merge(&mut self, other: Snapshot) {
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.claimed_tx_ids.extend(other.claimed_tx_ids);
+        self.claimed_tx_ids.sort_unstable();
+        self.claimed_tx_ids.dedup();
+    }
+
+    /// Partition into one snapshot per shard, following `shard_for`. Claimed ids only guard
+    /// against reuse so every shard gets all of them.
+    pub fn split(&self, shard_count: usize) -> Vec<Snapshot> {
+        let mut shards: Vec<Snapshot> = (0..shard_count)
+            .map(|_| Snapshot {
+                claimed_tx_ids: self.claimed_tx_ids.clone(),
+                ..Default::default()
+            })
+            .collect();
+        for (client_id, account) in &self.accounts {
+            shards[shard_for(*client_id, shard_count)]
+                .accounts
+                .insert(*client_id, account.clone());
+        }
+        for (tx_id, transaction) in &self.transactions {
+            shards[shard_for(transaction.client_id, shard_count)]
+                .transactions
+                .insert(*tx_id, transaction.clone());
+        }
+        shards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispute::DisputeState;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<()> {
+        let mut snapshot = Snapshot::default();
+        snapshot.accounts.insert(
+            1,
+            Account {
+                available: "1.5".parse()?,
+                held: "2.25".parse()?,
+                locked: Some(Locked {
+                    reason_for_lock: LockReason::Chargeback,
+                }),
+                ..Default::default()
+            },
+        );
+        snapshot.transactions.insert(
+            7,
+            TransactionState {
+                client_id: 1,
+                kind: TransactionKind::Withdrawal,
+                amount: "2.25".parse()?,
+                dispute_state: DisputeState::Disputed,
+            },
+        );
+        snapshot.claimed_tx_ids = vec![3];
+
+        let dir = tempdir()?;
+        let path = dir.path().join("snapshot.json");
+        snapshot.write(&path)?;
+        assert_eq!(Snapshot::read(&path)?, snapshot);
+
+        let split = snapshot.split(2);
+        assert_eq!(split[0].accounts.len(), 0);
+        assert_eq!(split[1].transactions.len(), 1);
+        assert_eq!(split[0].claimed_tx_ids, vec![3]);
+
+        let mut merged = Snapshot::default();
+        for shard in split {
+            merged.merge(shard);
+        }
+        assert_eq!(merged, snapshot);
+
+        std::fs::write(&path, r#"{"version": 99}"#)?;
+        let error = Snapshot::read(&path).unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Unsupported snapshot version 99, expected 1"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::error::*;
+use crate::handlers::*;
+use crate::transaction::*;
+use crate::types::*;
+
+use eyre::*;
+use std::result::Result::Ok;
+use std::{
+    sync::{mpsc::SyncSender, Arc},
+    thread::{self, JoinHandle},
+};
+
+/// Where the transactions of a run come from. A source is the reader stage of the pipeline: it
+/// sends transactions tagged with the input and line they came from, and rejects any it can't
+/// read.
+pub trait TransactionSource {
+    /// The inputs transactions are tagged with, in the order they are read. Resuming from a wal
+    /// and ordering the report by insertion go by these.
+    fn input_names(&self) -> Vec<String>;
+
+    /// Start sending on a thread, which returns the reader's summary.
+    fn start(
+        self,
+        tx: SyncSender<Sourced<AnyTransaction>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Result<JoinHandle<Result<StageSummary>>>;
+}
+
+/// Csv or jsonl files read one after the other, `-` being stdin.
+#[derive(Debug, Clone)]
+pub struct FileSource {
+    file_names: Vec<String>,
+    format: Option<InputFormat>,
+    reader_threads: u8,
+}
+
+impl FileSource {
+    /// The format is picked from the files' extensions.
+    pub fn new(file_names: Vec<String>) -> Self {
+        Self {
+            file_names,
+            format: None,
+            reader_threads: 1,
+        }
+    }
+
+    pub fn stdin() -> Self {
+        Self::new(vec![STDIN.to_string()])
+    }
+
+    /// Read every input as `format`, whatever its extension.
+    pub fn with_format(mut self, format: InputFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Parse csv files in chunks on `reader_threads` threads.
+    pub fn with_reader_threads(mut self, reader_threads: u8) -> Self {
+        self.reader_threads = reader_threads;
+        self
+    }
+}
+
+impl TransactionSource for FileSource {
+    fn input_names(&self) -> Vec<String> {
+        self.file_names.clone()
+    }
+
+    fn start(
+        self,
+        tx: SyncSender<Sourced<AnyTransaction>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Result<JoinHandle<Result<StageSummary>>> {
+        let format = match self.format {
+            Some(format) => format,
+            None => InputFormat::detect(&self.file_names)?,
+        };
+        match format {
+            InputFormat::Csv => {
+                CsvReader::new(tx, rejections).start(self.file_names, self.reader_threads)
+            }
+            InputFormat::Jsonl => JsonlReader::new(tx, rejections).start(self.file_names),
+        }
+    }
+}
+
+/// Transactions held in memory or made up by a generator, tagged as lines of a single input
+/// counting from 1.
+pub struct IterSource<I> {
+    name: String,
+    transactions: I,
+}
+
+impl<I: Iterator<Item = AnyTransaction>> IterSource<I> {
+    pub fn new(
+        name: impl Into<String>,
+        transactions: impl IntoIterator<Item = AnyTransaction, IntoIter = I>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            transactions: transactions.into_iter(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = AnyTransaction> + Send + 'static> TransactionSource for IterSource<I> {
+    fn input_names(&self) -> Vec<String> {
+        vec![self.name.clone()]
+    }
+
+    fn start(
+        self,
+        tx: SyncSender<Sourced<AnyTransaction>>,
+        _rejections: SyncSender<Rejection>,
+    ) -> Result<JoinHandle<Result<StageSummary>>> {
+        let name: Arc<str> = self.name.into();
+        let transactions = self.transactions;
+        let handle = thread::spawn(move || {
+            let mut summary = StageSummary::new(Stage::Reader);
+            for (line, transaction) in (1..).zip(transactions) {
+                summary.accepted += 1;
+                if tx
+                    .send(Sourced::new(name.clone(), line, transaction))
+                    .is_err()
+                {
+                    break; // Receiver has been dropped
+                }
+            }
+            Ok(summary)
+        });
+
+        Ok(handle)
+    }
+}
+
+impl IterSource<std::iter::Take<GeneratedTransactions>> {
+    /// `count` generated transactions, as an input named `generated`.
+    pub fn generated(count: usize, seed: u64) -> Self {
+        Self::new("generated", GeneratedTransactions::new(seed).take(count))
+    }
+}
+
+/// An endless, deterministic mix of every command type for tests and benchmarks, including
+/// cross-client disputes, reused tx ids and references to unknown txs. Tx ids count up from 1.
+#[derive(Debug, Clone)]
+pub struct GeneratedTransactions {
+    state: u64,
+    client_count: u32,
+    tx_id: TxId,
+}
+
+impl GeneratedTransactions {
+    /// Spread over 20 clients.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            client_count: 20,
+            tx_id: 0,
+        }
+    }
+
+    pub fn with_clients(mut self, client_count: u16) -> Self {
+        self.client_count = client_count.into();
+        self
+    }
+
+    /// A linear congruential generator, only needs to be repeatable.
+    fn next_below(&mut self, bound: u32) -> u32 {
+        self.state = self
+            .state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.state >> 33) % bound as u64) as u32
+    }
+}
+
+impl Iterator for GeneratedTransactions {
+    type Item = AnyTransaction;
+
+    fn next(&mut self) -> Option<AnyTransaction> {
+        self.tx_id = self.tx_id.checked_add(1)?;
+        let client_id = self.next_below(self.client_count) as ClientId;
+        let earlier_tx_id = self.next_below(self.tx_id.saturating_add(5));
+        let amount = format!("{}.{:04}", self.next_below(100), self.next_below(10_000))
+            .parse()
+            .ok();
+        let (command_type, tx_id, amount) = match self.next_below(10) {
+            0..=3 => (CommandType::Deposit, self.tx_id, amount),
+            4..=5 => (CommandType::Withdrawal, self.tx_id, amount),
+            6 => (CommandType::Deposit, earlier_tx_id, amount),
+            7 => (CommandType::Dispute, earlier_tx_id, None),
+            8 => (CommandType::Resolve, earlier_tx_id, None),
+            _ => (CommandType::Chargeback, earlier_tx_id, None),
+        };
+        Some(AnyTransaction {
+            command_type,
+            client_id,
+            tx_id,
+            amount,
+        })
+    }
+}
+
+/// Read as an input named `memory`.
+impl TransactionSource for Vec<AnyTransaction> {
+    fn input_names(&self) -> Vec<String> {
+        vec!["memory".to_string()]
+    }
+
+    fn start(
+        self,
+        tx: SyncSender<Sourced<AnyTransaction>>,
+        rejections: SyncSender<Rejection>,
+    ) -> Result<JoinHandle<Result<StageSummary>>> {
+        IterSource::new("memory", self).start(tx, rejections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn test_iter_source() -> Result<()> {
+        let deposits =
+            (1..=3).map(|tx_id| AnyTransaction::new(CommandType::Deposit, 1, tx_id, "1.0"));
+        let source = IterSource::new("generated", deposits);
+        assert_eq!(source.input_names(), vec!["generated"]);
+
+        let (tx, rx) = sync_channel(16);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
+        let summary = source.start(tx, tx_rejection)?.join().unwrap()?;
+        assert_eq!(summary.accepted, 3);
+
+        let sourced: Vec<(String, u64, u32)> = rx
+            .iter()
+            .map(|s| (s.file.to_string(), s.line, s.value.tx_id))
+            .collect();
+        assert_eq!(
+            sourced,
+            vec![
+                ("generated".to_string(), 1, 1),
+                ("generated".to_string(), 2, 2),
+                ("generated".to_string(), 3, 3),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generated_transactions() -> Result<()> {
+        let transactions: Vec<AnyTransaction> = GeneratedTransactions::new(3).take(1_000).collect();
+        assert_eq!(
+            GeneratedTransactions::new(3)
+                .take(1_000)
+                .collect::<Vec<_>>(),
+            transactions
+        );
+        assert!(transactions.iter().all(|tx| tx.client_id < 20));
+        for command_type in [CommandType::Deposit, CommandType::Chargeback] {
+            assert!(transactions
+                .iter()
+                .any(|tx| tx.command_type == command_type));
+        }
+
+        // Written as csv they read back the same.
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let mut wtr = csv::Writer::from_path(temp_file.path())?;
+        for transaction in &transactions {
+            wtr.serialize(transaction)?;
+        }
+        wtr.flush()?;
+
+        let (tx, rx) = sync_channel(2_000);
+        let (tx_rejection, _rx_rejection) = sync_channel(16);
+        let source = FileSource::new(vec![temp_file.path().to_str().unwrap().to_string()]);
+        source.start(tx, tx_rejection)?.join().unwrap()?;
+        let read: Vec<AnyTransaction> = rx.iter().map(|s| s.value).collect();
+        assert_eq!(read, transactions);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
+use crate::dispute::DisputeState;
+use crate::error::RejectionReason;
 use crate::types::*;
-use serde::Deserialize;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{fmt, sync::Arc};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TransactionCommand {
@@ -10,14 +14,23 @@ pub enum TransactionCommand {
     Chargeback(Chargeback),
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
-pub struct DepositState {
+/// The kinds of transaction that move funds and can therefore be disputed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionState {
     pub client_id: ClientId,
-    pub amount: Balance,
-    pub is_under_dispute: bool,
+    pub kind: TransactionKind,
+    pub amount: Money,
+    pub dispute_state: DisputeState,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// A row of the input, with the same columns when written back out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AnyTransaction {
     #[serde(rename = "type")]
     pub command_type: CommandType,
@@ -25,11 +38,25 @@ pub struct AnyTransaction {
     pub tx_id: TxId,
     #[serde(rename = "client")]
     pub client_id: ClientId,
-    pub amount: Option<Balance>,
+    pub amount: Option<Money>,
 }
 
-#[derive(Debug, Clone, Deserialize, Default, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[cfg(test)]
+impl AnyTransaction {
+    /// A transaction for tests. An empty amount is left out.
+    pub fn new(command_type: CommandType, client_id: ClientId, tx_id: TxId, amount: &str) -> Self {
+        Self {
+            command_type,
+            client_id,
+            tx_id,
+            amount: (!amount.is_empty()).then(|| amount.parse().unwrap()),
+        }
+    }
+}
+
+/// Parsed case-insensitively, anything unrecognised becomes `Unknown` and is rejected later as
+/// an unknown command type rather than a malformed row.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub enum CommandType {
     Deposit,
     Withdrawal,
@@ -40,18 +67,95 @@ pub enum CommandType {
     Unknown,
 }
 
+impl CommandType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandType::Deposit => "deposit",
+            CommandType::Withdrawal => "withdrawal",
+            CommandType::Dispute => "dispute",
+            CommandType::Resolve => "resolve",
+            CommandType::Chargeback => "chargeback",
+            CommandType::Unknown => "unknown",
+        }
+    }
+}
+
+impl From<&str> for CommandType {
+    fn from(s: &str) -> Self {
+        [
+            CommandType::Deposit,
+            CommandType::Withdrawal,
+            CommandType::Dispute,
+            CommandType::Resolve,
+            CommandType::Chargeback,
+        ]
+        .into_iter()
+        .find(|command_type| command_type.as_str().eq_ignore_ascii_case(s.trim()))
+        .unwrap_or_default()
+    }
+}
+
+impl Serialize for CommandType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct CommandTypeVisitor;
+
+        impl Visitor<'_> for CommandTypeVisitor {
+            type Value = CommandType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<CommandType, E> {
+                Ok(CommandType::from(v))
+            }
+        }
+
+        deserializer.deserialize_str(CommandTypeVisitor)
+    }
+}
+
+/// A value tagged with the input file and line it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sourced<T> {
+    /// Name of the input, `-` for stdin.
+    pub file: Arc<str>,
+    pub line: u64,
+    pub value: T,
+}
+
+impl<T> Sourced<T> {
+    pub fn new(file: Arc<str>, line: u64, value: T) -> Self {
+        Self { file, line, value }
+    }
+
+    /// Replace the value, keeping where it came from.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Sourced<U> {
+        Sourced::new(self.file, self.line, f(self.value))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Deposit {
     pub client_id: ClientId,
     pub tx_id: TxId,
-    pub amount: Balance,
+    pub amount: Money,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Withdrawal {
     pub client_id: ClientId,
     pub tx_id: TxId,
-    pub amount: Balance,
+    pub amount: Money,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -71,3 +175,64 @@ pub struct Chargeback {
     pub client_id: ClientId,
     pub tx_id: TxId,
 }
+
+impl From<&TransactionCommand> for AnyTransaction {
+    fn from(tx_command: &TransactionCommand) -> Self {
+        let (command_type, client_id, tx_id, amount) = match tx_command {
+            TransactionCommand::Deposit(d) => {
+                (CommandType::Deposit, d.client_id, d.tx_id, Some(d.amount))
+            }
+            TransactionCommand::Withdrawal(w) => (
+                CommandType::Withdrawal,
+                w.client_id,
+                w.tx_id,
+                Some(w.amount),
+            ),
+            TransactionCommand::Dispute(d) => (CommandType::Dispute, d.client_id, d.tx_id, None),
+            TransactionCommand::Resolve(r) => (CommandType::Resolve, r.client_id, r.tx_id, None),
+            TransactionCommand::Chargeback(c) => {
+                (CommandType::Chargeback, c.client_id, c.tx_id, None)
+            }
+        };
+
+        AnyTransaction {
+            command_type,
+            tx_id,
+            client_id,
+            amount,
+        }
+    }
+}
+
+/// Validation over required fields is done here.
+impl TryFrom<&AnyTransaction> for TransactionCommand {
+    type Error = RejectionReason;
+
+    fn try_from(tx: &AnyTransaction) -> Result<Self, Self::Error> {
+        match tx.command_type {
+            CommandType::Deposit => Ok(TransactionCommand::Deposit(Deposit {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+                amount: tx.amount.ok_or(RejectionReason::MissingAmount)?,
+            })),
+            CommandType::Withdrawal => Ok(TransactionCommand::Withdrawal(Withdrawal {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+                amount: tx.amount.ok_or(RejectionReason::MissingAmount)?,
+            })),
+            CommandType::Dispute => Ok(TransactionCommand::Dispute(Dispute {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            })),
+            CommandType::Resolve => Ok(TransactionCommand::Resolve(Resolve {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            })),
+            CommandType::Chargeback => Ok(TransactionCommand::Chargeback(Chargeback {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            })),
+            CommandType::Unknown => Err(RejectionReason::UnknownCommandType),
+        }
+    }
+}
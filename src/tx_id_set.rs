@@ -0,0 +1,102 @@
+use crate::types::*;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+const WORDS_PER_PAGE: usize = 1024;
+const BITS_PER_PAGE: usize = BITS_PER_WORD * WORDS_PER_PAGE;
+const PAGE_COUNT: usize = (TxId::MAX as usize + 1) / BITS_PER_PAGE;
+
+/// A bitset over the full `TxId` space.
+///
+/// Pages of 64k ids are only allocated once an id inside them is inserted, so a sparse set
+/// costs little more than the page table (512KiB) and a dense one tops out at 512MiB.
+pub struct TxIdSet {
+    pages: Vec<Option<Box<[u64; WORDS_PER_PAGE]>>>,
+}
+
+impl Default for TxIdSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxIdSet {
+    pub fn new() -> Self {
+        Self {
+            pages: vec![None; PAGE_COUNT],
+        }
+    }
+
+    /// Returns whether the id was newly inserted.
+    pub fn insert(&mut self, tx_id: TxId) -> bool {
+        let (page, word, bit) = TxIdSet::locate(tx_id);
+        let page = self.pages[page].get_or_insert_with(|| Box::new([0; WORDS_PER_PAGE]));
+        let is_new = page[word] & bit == 0;
+        page[word] |= bit;
+        is_new
+    }
+
+    pub fn contains(&self, tx_id: TxId) -> bool {
+        let (page, word, bit) = TxIdSet::locate(tx_id);
+        self.pages[page]
+            .as_ref()
+            .is_some_and(|page| page[word] & bit != 0)
+    }
+
+    /// Every id in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(page_index, page)| page.as_ref().map(|page| (page_index, page)))
+            .flat_map(|(page_index, page)| {
+                page.iter().enumerate().flat_map(move |(word_index, word)| {
+                    let base = page_index * BITS_PER_PAGE + word_index * BITS_PER_WORD;
+                    (0..BITS_PER_WORD)
+                        .filter(move |bit| word & (1 << bit) != 0)
+                        .map(move |bit| (base + bit) as TxId)
+                })
+            })
+    }
+
+    fn locate(tx_id: TxId) -> (usize, usize, u64) {
+        let index = tx_id as usize;
+        let page = index / BITS_PER_PAGE;
+        let word = (index % BITS_PER_PAGE) / BITS_PER_WORD;
+        let bit = 1 << (index % BITS_PER_WORD);
+        (page, word, bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tx_id_set() {
+        let mut set = TxIdSet::new();
+
+        for tx_id in [0, 1, 63, 64, BITS_PER_PAGE as TxId, TxId::MAX] {
+            assert!(!set.contains(tx_id));
+            assert!(set.insert(tx_id));
+            assert!(!set.insert(tx_id));
+            assert!(set.contains(tx_id));
+        }
+
+        assert!(set.insert(2));
+        assert!(set.insert(TxId::MAX - 1));
+        assert_eq!(set.pages.iter().filter(|p| p.is_some()).count(), 3);
+
+        let ids: Vec<TxId> = set.iter().collect();
+        let expected = [
+            0,
+            1,
+            2,
+            63,
+            64,
+            BITS_PER_PAGE as TxId,
+            TxId::MAX - 1,
+            TxId::MAX,
+        ];
+        assert_eq!(ids, expected);
+    }
+}
@@ -1,4 +1,4 @@
+pub use crate::money::{Money, MoneySum};
+
 pub type ClientId = u16;
 pub type TxId = u32;
-// TODO: 4dp
-pub type Balance = f64;
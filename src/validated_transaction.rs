@@ -1,6 +1,9 @@
+use crate::transaction::TransactionKind;
 use crate::types::*;
 
-#[derive(Debug, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ValidatedTransactionCommand {
     Deposit(ValidDeposit),
     Withdrawal(ValidWithdrawal),
@@ -9,40 +12,82 @@ pub enum ValidatedTransactionCommand {
     Chargeback(ValidChargeback),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl ValidatedTransactionCommand {
+    /// The client whose account the command acts upon.
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            ValidatedTransactionCommand::Deposit(deposit) => deposit.client_id,
+            ValidatedTransactionCommand::Withdrawal(withdrawal) => withdrawal.client_id,
+            ValidatedTransactionCommand::Dispute(dispute) => dispute.contended_client_id,
+            ValidatedTransactionCommand::Resolve(resolve) => resolve.contended_client_id,
+            ValidatedTransactionCommand::Chargeback(chargeback) => chargeback.contended_client_id,
+        }
+    }
+
+    /// The tx the command creates or acts upon.
+    pub fn tx_id(&self) -> TxId {
+        match self {
+            ValidatedTransactionCommand::Deposit(deposit) => deposit.tx_id,
+            ValidatedTransactionCommand::Withdrawal(withdrawal) => withdrawal.tx_id,
+            ValidatedTransactionCommand::Dispute(dispute) => dispute.tx_id,
+            ValidatedTransactionCommand::Resolve(resolve) => resolve.tx_id,
+            ValidatedTransactionCommand::Chargeback(chargeback) => chargeback.tx_id,
+        }
+    }
+}
+
+/// How disputed funds enter the held balance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HoldDirection {
+    /// The disputed amount moves from available into held, as for a disputed deposit.
+    /// A resolve moves it back. A chargeback removes a deposit's funds from the account, while
+    /// for a withdrawal it releases the hold and returns the withdrawn funds.
+    FromAvailable,
+    /// The disputed amount is credited into held, as for a disputed withdrawal the client
+    /// wants returned. A resolve removes it again and a chargeback releases it to available.
+    #[default]
+    Credit,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidDeposit {
     pub tx_id: TxId,
     pub client_id: ClientId,
-    pub amount: Balance,
+    pub amount: Money,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidWithdrawal {
     pub tx_id: TxId,
     pub client_id: ClientId,
-    pub amount: Balance,
+    pub amount: Money,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidDispute {
     pub tx_id: TxId,
     pub raising_client_id: ClientId,
     pub contended_client_id: ClientId,
-    pub amount: Balance,
+    pub amount: Money,
+    pub hold: HoldDirection,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidResolve {
     pub tx_id: TxId,
     pub raising_client_id: ClientId,
     pub contended_client_id: ClientId,
-    pub amount: Balance,
+    pub amount: Money,
+    pub hold: HoldDirection,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValidChargeback {
     pub tx_id: TxId,
     pub raising_client_id: ClientId,
     pub contended_client_id: ClientId,
-    pub amount: Balance,
+    pub amount: Money,
+    pub hold: HoldDirection,
+    /// What is charged back, a deposit's funds are taken away and a withdrawal's returned.
+    pub kind: TransactionKind,
 }
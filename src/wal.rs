@@ -0,0 +1,311 @@
+use crate::engine::*;
+use crate::error::*;
+use crate::types::*;
+use crate::validated_transaction::*;
+
+use eyre::*;
+use serde::{Deserialize, Serialize};
+use std::result::Result::Ok;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Bytes written to a segment before starting the next one.
+const SEGMENT_SIZE: u64 = 64 << 20;
+/// Entries appended between two fsyncs.
+const SYNC_BATCH: usize = 1024;
+/// Payload length and crc32 in front of each entry.
+const FRAME_HEADER_SIZE: u64 = 8;
+
+/// A change made to an engine, enough to make it again on replay.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WalRecord {
+    /// A command the engine accepted.
+    Applied(ValidatedTransactionCommand),
+    /// The tx id claimed by a rejected deposit or withdrawal.
+    Claimed(TxId),
+}
+
+impl WalRecord {
+    pub fn replay(self, engine: &mut Engine) -> Result<(), RejectionReason> {
+        match self {
+            WalRecord::Applied(validated_tx) => engine.commit(validated_tx).map(|_| ()),
+            WalRecord::Claimed(tx_id) => {
+                engine.claim(tx_id);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A record and the input row that caused it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub file: String,
+    pub line: u64,
+    pub record: WalRecord,
+}
+
+/// Append-only log of the changes made by one account manager shard, split into numbered
+/// segment files.
+///
+/// Every entry is framed with its length and crc32, so a write torn by a crash is detected and
+/// cut off when the log is next opened. Entries are fsynced in batches: anything lost with the
+/// last batch is recovered by re-reading the input after the last entry that survived.
+pub struct Wal {
+    dir: PathBuf,
+    shard: usize,
+    shard_count: usize,
+    segment: u64,
+    segment_len: u64,
+    wtr: BufWriter<File>,
+    unsynced: usize,
+}
+
+impl Wal {
+    /// Open the log of a shard in `dir`, passing every intact entry already in it to `replay`.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        shard: usize,
+        shard_count: usize,
+        mut replay: impl FnMut(WalEntry) -> Result<()>,
+    ) -> Result<Wal> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create wal directory {}", dir.display()))?;
+
+        let segments = list_segments(&dir, shard, shard_count)?;
+        let mut segment = 0;
+        let mut segment_len = 0;
+        for (i, seq) in segments.iter().enumerate() {
+            let path = segment_path(&dir, shard, shard_count, *seq);
+            let intact_len = replay_segment(&path, &mut replay)
+                .wrap_err_with(|| format!("Failed to replay wal segment {}", path.display()))?;
+
+            if intact_len < fs::metadata(&path)?.len() {
+                if i + 1 < segments.len() {
+                    return Err(eyre!("Corrupt wal segment {}", path.display()));
+                }
+                // Only the last write can have been torn by a crash.
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(intact_len)?;
+            }
+            segment = *seq;
+            segment_len = intact_len;
+        }
+
+        let wtr = BufWriter::new(open_segment(&dir, shard, shard_count, segment)?);
+        Ok(Wal {
+            dir,
+            shard,
+            shard_count,
+            segment,
+            segment_len,
+            wtr,
+            unsynced: 0,
+        })
+    }
+
+    /// Buffered until the next `sync`, which happens at least every `SYNC_BATCH` entries.
+    pub fn append(&mut self, entry: &WalEntry) -> Result<()> {
+        let payload = serde_json::to_vec(entry)?;
+        let frame_len = FRAME_HEADER_SIZE + payload.len() as u64;
+        if self.segment_len > 0 && self.segment_len + frame_len > SEGMENT_SIZE {
+            self.roll()?;
+        }
+
+        self.wtr.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.wtr
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.wtr.write_all(&payload)?;
+        self.segment_len += frame_len;
+
+        self.unsynced += 1;
+        if self.unsynced >= SYNC_BATCH {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Make every appended entry durable.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.wtr.flush()?;
+            self.wtr.get_ref().sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    fn roll(&mut self) -> Result<()> {
+        self.sync()?;
+        self.segment += 1;
+        self.segment_len = 0;
+        self.wtr = BufWriter::new(open_segment(
+            &self.dir,
+            self.shard,
+            self.shard_count,
+            self.segment,
+        )?);
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, shard: usize, shard_count: usize, seq: u64) -> PathBuf {
+    dir.join(format!("shard-{}-of-{}.{:08}.wal", shard, shard_count, seq))
+}
+
+fn open_segment(dir: &Path, shard: usize, shard_count: usize, seq: u64) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, shard, shard_count, seq))?;
+    // Persist the directory entry too, or a new segment can vanish with everything in it.
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(file)
+}
+
+/// Sequence numbers of the shard's segments, in order. Segments are only valid for the shard
+/// count that wrote them, since clients are assigned to shards by it.
+fn list_segments(dir: &Path, shard: usize, shard_count: usize) -> Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let file_name = dir_entry?.file_name();
+        let Some(name) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix("shard-"))
+            .and_then(|name| name.strip_suffix(".wal"))
+        else {
+            continue;
+        };
+        let Some((shard_part, seq)) = name.split_once('.') else {
+            continue;
+        };
+        let Some((segment_shard, segment_shard_count)) = shard_part.split_once("-of-") else {
+            continue;
+        };
+
+        if segment_shard_count != shard_count.to_string() {
+            return Err(eyre!(
+                "The wal in {} was written with {} shards, resume with the same number",
+                dir.display(),
+                segment_shard_count
+            ));
+        }
+        if segment_shard == shard.to_string() {
+            segments.push(seq.parse()?);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Replay the intact entries of a segment, returning the length they take up.
+fn replay_segment(path: &Path, replay: &mut impl FnMut(WalEntry) -> Result<()>) -> Result<u64> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    let mut intact_len = 0;
+
+    loop {
+        let mut header = [0; FRAME_HEADER_SIZE as usize];
+        if !read_frame_part(&mut rdr, &mut header)? {
+            break;
+        }
+        let payload_len = u32::from_le_bytes(header[..4].try_into()?) as u64;
+        let checksum = u32::from_le_bytes(header[4..].try_into()?);
+        if payload_len > SEGMENT_SIZE {
+            break;
+        }
+
+        let mut payload = vec![0; payload_len as usize];
+        if !read_frame_part(&mut rdr, &mut payload)? || crc32fast::hash(&payload) != checksum {
+            break;
+        }
+
+        replay(serde_json::from_slice(&payload)?)?;
+        intact_len += FRAME_HEADER_SIZE + payload_len;
+    }
+
+    Ok(intact_len)
+}
+
+/// Returns false if the segment ends first.
+fn read_frame_part(rdr: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    match rdr.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn entry(line: u64) -> WalEntry {
+        WalEntry {
+            file: "day-1.csv".to_string(),
+            line,
+            record: WalRecord::Applied(ValidatedTransactionCommand::Deposit(ValidDeposit {
+                client_id: 1,
+                tx_id: line as TxId,
+                amount: "1.5".parse().unwrap(),
+            })),
+        }
+    }
+
+    fn replayed(dir: &Path) -> Result<Vec<u64>> {
+        let mut lines = Vec::new();
+        let mut wal = Wal::open(dir, 0, 1, |entry| {
+            lines.push(entry.line);
+            Ok(())
+        })?;
+        wal.sync()?;
+        Ok(lines)
+    }
+
+    #[test]
+    fn test_wal_cuts_off_torn_writes() -> Result<()> {
+        let dir = tempdir()?;
+
+        let mut wal = Wal::open(dir.path(), 0, 1, |_| Ok(()))?;
+        for line in 2..5 {
+            wal.append(&entry(line))?;
+        }
+        wal.sync()?;
+        drop(wal);
+        assert_eq!(replayed(dir.path())?, vec![2, 3, 4]);
+
+        // Half of the last entry made it to disk.
+        let path = segment_path(dir.path(), 0, 1, 0);
+        let len = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 5)?;
+        assert_eq!(replayed(dir.path())?, vec![2, 3]);
+
+        // Appending carries on from the last intact entry.
+        let mut wal = Wal::open(dir.path(), 0, 1, |_| Ok(()))?;
+        wal.append(&entry(5))?;
+        wal.sync()?;
+        drop(wal);
+        assert_eq!(replayed(dir.path())?, vec![2, 3, 5]);
+
+        // A flipped bit fails the checksum, dropping that entry and everything after it.
+        let mut bytes = fs::read(&path)?;
+        let second_entry = bytes.len() / 3 + 10;
+        bytes[second_entry] ^= 1;
+        fs::write(&path, bytes)?;
+        assert_eq!(replayed(dir.path())?, vec![2]);
+
+        assert!(Wal::open(dir.path(), 0, 2, |_| Ok(())).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Runs the pipeline on a generated input and on one twice as large, checking that peak memory
+//! doesn't grow with the input. Kept to its own test binary so no other test shares the process.
+
+use eyre::Result;
+use kraken::pipeline::*;
+use kraken::report::*;
+use kraken::source::*;
+use kraken::transaction::*;
+use std::fs;
+
+/// Disputes of txs that were never made. Every one is read, routed and rejected by a shard but
+/// leaves no state behind, so only buffering between the stages could take up memory.
+fn disputes(count: u32) -> IterSource<impl Iterator<Item = AnyTransaction>> {
+    IterSource::new(
+        "disputes",
+        (1..=count).map(|tx_id| AnyTransaction {
+            command_type: CommandType::Dispute,
+            client_id: (tx_id % 20) as u16,
+            tx_id,
+            amount: None,
+        }),
+    )
+}
+
+/// Peak resident set size of this process in KiB, None where /proc isn't available.
+fn peak_rss_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[test]
+fn test_memory_stays_flat_as_input_grows() -> Result<()> {
+    let run = |count| -> Result<Option<u64>> {
+        let summaries = process_transactions(
+            disputes(count),
+            &mut MemorySink::new(),
+            Options {
+                rejections_filename: Some("/dev/null".to_string()),
+                shard_count: 2,
+                channel_capacity: 64,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(summaries[0].accepted, count as u64);
+        Ok(peak_rss_kib())
+    };
+
+    let (Some(peak), Some(peak_twice_as_large)) = (run(200_000)?, run(400_000)?) else {
+        return Ok(()); // No way to measure
+    };
+    // Buffering the extra 200k messages would take well over 10MiB.
+    assert!(
+        peak_twice_as_large - peak < 2 * 1024,
+        "Peak memory grew from {}KiB to {}KiB",
+        peak,
+        peak_twice_as_large
+    );
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Kills the binary at random points of a run with a write-ahead log and checks that running it
+//! again to completion gives the same balances as a run that never crashed.
+
+use eyre::Result;
+use kraken::pipeline::*;
+use kraken::policy::*;
+use kraken::report::*;
+use kraken::source::*;
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Generated transactions split over two files.
+fn write_inputs(dir: &Path, count: usize, seed: u64) -> Result<Vec<String>> {
+    let mut transactions = GeneratedTransactions::new(seed).with_clients(50);
+    let mut files = Vec::new();
+    for day in [1, 2] {
+        let path = dir.join(format!("day-{}.csv", day));
+        let mut wtr = csv::Writer::from_path(&path)?;
+        for transaction in transactions.by_ref().take(count / 2) {
+            wtr.serialize(transaction)?;
+        }
+        wtr.flush()?;
+        files.push(path.to_string_lossy().into_owned());
+    }
+    Ok(files)
+}
+
+fn kraken(inputs: &[String], args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_kraken"));
+    command
+        .args(inputs)
+        .args(["--rejections", "/dev/null", "--operator-client", "0"])
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    command
+}
+
+fn sorted_report(command: &mut Command) -> Result<Vec<String>> {
+    let output = command.output()?;
+    assert!(output.status.success());
+    Ok(sorted_lines(&String::from_utf8(output.stdout)?))
+}
+
+fn sorted_lines(report: &str) -> Vec<String> {
+    let mut lines: Vec<String> = report.lines().map(str::to_string).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn test_resumes_after_crashes() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let inputs = write_inputs(dir.path(), 40_000, 3)?;
+    let wal_dir = dir.path().join("wal");
+    let wal_dir = wal_dir.to_str().unwrap();
+
+    // The same run without a wal, in process.
+    let started = Instant::now();
+    let mut sink = CsvSink::new(Vec::new());
+    process_transactions(
+        FileSource::new(inputs.clone()),
+        &mut sink,
+        Options {
+            rejections_filename: Some("/dev/null".to_string()),
+            policy: Policy {
+                operator_client_id: Some(0),
+                ..Default::default()
+            },
+            shard_count: 3,
+            ..Default::default()
+        },
+    )?;
+    let expected = sorted_lines(&String::from_utf8(sink.into_inner()?)?);
+    let run_time = started.elapsed().as_millis() as u64 + 1;
+
+    let mut state = 17u64;
+    let mut crashes = 0;
+    for _ in 0..6 {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let delay = (state >> 33) % run_time;
+
+        let mut child = kraken(&inputs, &["--shards", "3", "--wal", wal_dir]).spawn()?;
+        thread::sleep(Duration::from_millis(delay));
+        if child.try_wait()?.is_none() {
+            crashes += 1;
+        }
+        child.kill()?;
+        child.wait()?;
+    }
+    assert!(crashes > 0, "Every run finished before it was killed");
+
+    let resumed = sorted_report(&mut kraken(&inputs, &["--shards", "3", "--wal", wal_dir]))?;
+    assert_eq!(resumed, expected);
+
+    // Running a finished batch again applies nothing twice.
+    let rerun = sorted_report(&mut kraken(&inputs, &["--shards", "3", "--wal", wal_dir]))?;
+    assert_eq!(rerun, expected);
+
+    let error = kraken(&inputs, &["--shards", "2", "--wal", wal_dir]).output()?;
+    assert!(!error.status.success());
+
+    Ok(())
+}